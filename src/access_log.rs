@@ -0,0 +1,142 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    net::{IpAddr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::ConnectInfo,
+    http::{header, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use hyper::body::HttpBody;
+use serde::Deserialize;
+
+use crate::rate_limit;
+
+/// Layout of the access log lines, both followed by the duration of the request in microseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// Common Log Format: `host ident user [time] "request" status bytes`
+    Clf,
+    /// Combined Log Format, the common one followed by `"referer" "user-agent"`
+    Combined,
+}
+
+/// Lines of completed requests, written apart from the tracing output
+pub struct AccessLog {
+    format: Format,
+    trust_forwarded_for: bool,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl AccessLog {
+    /// Log to the end of the file at `path`, or to stdout
+    pub fn new(format: Format, path: Option<&Path>, trust_forwarded_for: bool) -> io::Result<Self> {
+        let out: Box<dyn Write + Send> = match path {
+            Some(path) => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+            None => Box::new(io::stdout()),
+        };
+
+        Ok(Self {
+            format,
+            trust_forwarded_for,
+            out: Mutex::new(out),
+        })
+    }
+
+    fn write(&self, line: &str) {
+        let mut out = self.out.lock().unwrap();
+        if let Err(err) = out.write_all(line.as_bytes()).and_then(|()| out.flush()) {
+            tracing::warn!("Failed to write the access log: {err}");
+        }
+    }
+}
+
+/// Log every request once its response is ready, streamed bodies of unknown size logged as `-` bytes
+pub async fn log<B>(access_log: Arc<AccessLog>, req: Request<B>, next: Next<B>) -> Response {
+    let start = Instant::now();
+    let time = SystemTime::now();
+    // Clients of a Unix socket have no address, they are the local reverse proxy
+    let host = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|&ConnectInfo(addr)| rate_limit::client_ip(req.headers(), addr.ip(), access_log.trust_forwarded_for));
+    let request_line = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+    let (referer, user_agent) = (
+        header_field(req.headers(), header::REFERER),
+        header_field(req.headers(), header::USER_AGENT),
+    );
+
+    let resp = next.run(req).await;
+
+    let bytes = resp
+        .body()
+        .size_hint()
+        .exact()
+        .map_or_else(|| "-".to_string(), |len| len.to_string());
+    let mut line = format!(
+        "{} - - [{}] \"{}\" {} {bytes}",
+        host.map_or_else(|| "-".to_string(), |host: IpAddr| host.to_string()),
+        clf_time(time),
+        escape(&request_line),
+        resp.status().as_u16(),
+    );
+    if access_log.format == Format::Combined {
+        line.push_str(&format!(" \"{referer}\" \"{user_agent}\""));
+    }
+    line.push_str(&format!(" {}\n", start.elapsed().as_micros()));
+    access_log.write(&line);
+
+    resp
+}
+
+/// Header value quoted in a field of the line, `-` when absent
+fn header_field(headers: &HeaderMap, name: header::HeaderName) -> String {
+    headers.get(name).map_or_else(
+        || "-".to_string(),
+        |value| escape(&String::from_utf8_lossy(value.as_bytes())),
+    )
+}
+
+/// Escape the quotes and backslashes of a quoted field, as Apache does
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// `10/Oct/2000:13:55:36 +0000`, always in UTC
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // Civil date of a day count since the epoch, from Howard Hinnant's `civil_from_days`
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
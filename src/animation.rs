@@ -0,0 +1,167 @@
+use std::{io::Cursor, num::NonZeroU32};
+
+use fast_image_resize as fir;
+use image::{
+    codecs::{gif::GifDecoder, webp::WebPDecoder},
+    AnimationDecoder, Frames, ImageError, ImageFormat,
+};
+
+use crate::{
+    color::Color,
+    effects::Effects,
+    resize::{self, ResizePlan},
+};
+
+/// Frames of an animated GIF or WebP, all covering the whole canvas
+pub struct Animation {
+    pub frames: Vec<AnimationFrame>,
+    /// Number of times the animation plays, 0 for forever like the WebP `ANIM` chunk
+    pub loop_count: u16,
+}
+
+pub struct AnimationFrame {
+    /// RGBA pixels
+    pub image: fir::Image<'static>,
+    pub delay_ms: u32,
+}
+
+impl Animation {
+    pub fn width(&self) -> NonZeroU32 {
+        self.frames[0].image.width()
+    }
+
+    pub fn height(&self) -> NonZeroU32 {
+        self.frames[0].image.height()
+    }
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Image(ImageError),
+    /// The animation has more frames than allowed
+    TooManyFrames,
+}
+
+impl From<ImageError> for DecodeError {
+    fn from(err: ImageError) -> Self {
+        DecodeError::Image(err)
+    }
+}
+
+/// Decode every frame of an animated GIF or WebP, `None` for any other image, animations of a single frame included
+pub fn decode(bytes: &[u8], max_frames: usize) -> Result<Option<Animation>, DecodeError> {
+    let (frames, loop_count) = match image::guess_format(bytes) {
+        Ok(ImageFormat::Gif) => (
+            GifDecoder::new(Cursor::new(bytes))?.into_frames(),
+            gif_loop_count(bytes),
+        ),
+        Ok(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(Cursor::new(bytes))?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            (decoder.into_frames(), webp_loop_count(bytes))
+        }
+        _ => return Ok(None),
+    };
+
+    let frames = collect_frames(frames, max_frames)?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    Ok(Some(Animation { frames, loop_count }))
+}
+
+fn collect_frames(frames: Frames, max_frames: usize) -> Result<Vec<AnimationFrame>, DecodeError> {
+    let mut decoded = Vec::new();
+    for frame in frames {
+        if decoded.len() == max_frames {
+            return Err(DecodeError::TooManyFrames);
+        }
+
+        let frame = frame?;
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let buffer = frame.into_buffer();
+        let image = fir::Image::from_vec_u8(
+            NonZeroU32::new(buffer.width()).unwrap(),
+            NonZeroU32::new(buffer.height()).unwrap(),
+            buffer.into_raw(),
+            fir::PixelType::U8x4,
+        )
+        .unwrap();
+
+        decoded.push(AnimationFrame {
+            image,
+            delay_ms: numer / denom.max(1),
+        });
+    }
+
+    Ok(decoded)
+}
+
+/// Read the `NETSCAPE2.0` application extension, without it a GIF plays once
+fn gif_loop_count(bytes: &[u8]) -> u16 {
+    const EXTENSION: &[u8] = b"NETSCAPE2.0\x03\x01";
+
+    let repeats = bytes
+        .windows(EXTENSION.len() + 2)
+        .find(|window| window.starts_with(EXTENSION))
+        .map(|window| u16::from_le_bytes([window[EXTENSION.len()], window[EXTENSION.len() + 1]]));
+
+    match repeats {
+        None => 1,
+        Some(0) => 0,
+        // The extension counts the repetitions after the first play
+        Some(repeats) => repeats.saturating_add(1),
+    }
+}
+
+/// Read the loop count of the `ANIM` chunk, defaulting to forever
+fn webp_loop_count(bytes: &[u8]) -> u16 {
+    let mut offset = 12;
+    while offset + 8 <= bytes.len() {
+        let size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if &bytes[offset..offset + 4] == b"ANIM" && size >= 6 && offset + 14 <= bytes.len() {
+            return u16::from_le_bytes([bytes[offset + 12], bytes[offset + 13]]);
+        }
+
+        offset += 8 + size + size % 2;
+    }
+
+    0
+}
+
+/// Resize and apply the effects to every frame of the animation the same way, padding them with `background` when
+/// the plan asks to. `linear` frames are resized in linear light.
+pub fn resize(
+    animation: &Animation,
+    plan: &ResizePlan,
+    effects: &Effects,
+    background: Color,
+    linear: bool,
+    resizer: &mut fir::Resizer,
+) -> Result<Animation, fir::DifferentTypesOfPixelsError> {
+    let frames = animation
+        .frames
+        .iter()
+        .map(|frame| {
+            let mut image = resize::resample(resizer, &frame.image, plan.crop, plan.width, plan.height, linear)?;
+            image = effects.apply(image);
+            if let Some((width, height)) = plan.canvas {
+                image = resize::pad(&image, width, height, background);
+            }
+            image = effects.orient(image);
+
+            Ok(AnimationFrame {
+                image,
+                delay_ms: frame.delay_ms,
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(Animation {
+        frames,
+        loop_count: animation.loop_count,
+    })
+}
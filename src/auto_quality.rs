@@ -0,0 +1,211 @@
+use std::{
+    num::NonZeroUsize,
+    sync::{LazyLock, Mutex},
+};
+
+use dssim_core::{Dssim, DssimImage};
+use fast_image_resize as fir;
+use image::{
+    error::{DecodingError, ImageFormatHint},
+    DynamicImage, ImageError, ImageFormat, RgbImage, RgbaImage,
+};
+use lru::LruCache;
+use rgb::FromSlice;
+use sha2::{Digest, Sha256};
+
+use crate::format::OutputFormat;
+
+/// Range of qualities searched, the highest is used when even it misses the target
+const MIN_QUALITY: u8 = 30;
+const MAX_QUALITY: u8 = 95;
+/// Encodes tried by a search, enough to bisect the range down to a single quality
+const MAX_ATTEMPTS: usize = 6;
+/// Searched qualities remembered, the least recently used forgotten first
+const CAPACITY: usize = 4096;
+
+/// Output cache key of a searched output, see `cache::cache_key`, and SHA-256 of its source so that a source changed
+/// at the origin gets searched again
+type Key = (String, [u8; 32]);
+
+/// Qualities picked by past searches, shared by every request
+static PICKED: LazyLock<Mutex<LruCache<Key, u8>>> =
+    LazyLock::new(|| Mutex::new(LruCache::new(NonZeroUsize::new(CAPACITY).unwrap())));
+
+/// Whether the outputs of `format` can be searched, the others are encoded at their usual quality
+pub fn supports(format: OutputFormat) -> bool {
+    // There is no AVIF decoder to compare its outputs with
+    matches!(format, OutputFormat::Jpeg | OutputFormat::Webp)
+}
+
+/// Encode `image` at the lowest quality keeping its output within `target` DSSIM of it, with the quality picked.
+///
+/// The quality is bisected within a few encodes, then remembered by the output cache `key` of the image and its
+/// `source` bytes so repeat requests encode once. Without a `key` the search is never skipped. Images too small to be
+/// compared are encoded at `fallback`.
+#[allow(clippy::too_many_arguments)]
+pub fn encode(
+    key: Option<&str>,
+    path: &str,
+    source: &[u8],
+    format: OutputFormat,
+    image: &fir::Image,
+    target: f64,
+    fallback: u8,
+    mut encode: impl FnMut(u8) -> image::ImageResult<Vec<u8>>,
+) -> image::ImageResult<(u8, Vec<u8>)> {
+    let key = key.map(|key| (key.to_owned(), Sha256::digest(source).into()));
+    let picked = key.as_ref().and_then(|key| PICKED.lock().unwrap().get(key).copied());
+    if let Some(quality) = picked {
+        return Ok((quality, encode(quality)?));
+    }
+
+    let dssim = Dssim::new();
+    let alpha = image.pixel_type() == fir::PixelType::U8x4;
+    let Some(original) = dssim_image(&dssim, image.buffer(), image, alpha) else {
+        return Ok((fallback, encode(fallback)?));
+    };
+
+    let (mut low, mut high) = (MIN_QUALITY, MAX_QUALITY);
+    let mut best = None;
+    for _ in 0..MAX_ATTEMPTS {
+        if low > high {
+            break;
+        }
+
+        let quality = low + (high - low) / 2;
+        let data = encode(quality)?;
+        let decoded = decode(format, &data)?;
+        let decoded = if alpha {
+            decoded.into_rgba8().into_raw()
+        } else {
+            decoded.into_rgb8().into_raw()
+        };
+        let distance = dssim_image(&dssim, &decoded, image, alpha)
+            .map_or(f64::INFINITY, |output| dssim.compare(&original, output).0.into());
+        tracing::debug!(path, quality, distance, "Tried an automatic quality");
+
+        if distance <= target {
+            best = Some((quality, data));
+            high = quality - 1;
+        } else {
+            low = quality + 1;
+        }
+    }
+
+    let (quality, data) = match best {
+        Some(best) => best,
+        None => (MAX_QUALITY, encode(MAX_QUALITY)?),
+    };
+    if let Some(key) = key {
+        PICKED.lock().unwrap().put(key, quality);
+    }
+
+    Ok((quality, data))
+}
+
+/// Decode an output to compare it, WebP through libwebp since `image`'s decoder strays too far from it
+fn decode(format: OutputFormat, data: &[u8]) -> image::ImageResult<DynamicImage> {
+    if format != OutputFormat::Webp {
+        return image::load_from_memory_with_format(data, ImageFormat::Jpeg);
+    }
+
+    let image = webp::Decoder::new(data).decode().ok_or_else(|| {
+        ImageError::Decoding(DecodingError::new(
+            ImageFormatHint::Exact(ImageFormat::WebP),
+            "libwebp failed to decode the output",
+        ))
+    })?;
+    let (width, height, pixels) = (image.width(), image.height(), image.to_vec());
+    Ok(if image.is_alpha() {
+        DynamicImage::ImageRgba8(RgbaImage::from_raw(width, height, pixels).unwrap())
+    } else {
+        DynamicImage::ImageRgb8(RgbImage::from_raw(width, height, pixels).unwrap())
+    })
+}
+
+/// `pixels` of the size of `image`, `None` when they are too small to be compared
+fn dssim_image(dssim: &Dssim, pixels: &[u8], image: &fir::Image, alpha: bool) -> Option<DssimImage<f32>> {
+    let (width, height) = (image.width().get() as usize, image.height().get() as usize);
+    if alpha {
+        dssim.create_image_rgba(pixels.as_rgba(), width, height)
+    } else {
+        dssim.create_image_rgb(pixels.as_rgb(), width, height)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, num::NonZeroU32};
+
+    use image::{codecs::jpeg::JpegEncoder, ColorType};
+
+    use super::*;
+    use crate::{
+        cache::{self, Cache},
+        Cli, Params,
+    };
+
+    /// Encodes a search of `image` made, with the quality picked
+    fn search(key: &str, source: &[u8], image: &fir::Image) -> (usize, u8) {
+        let encodes = Cell::new(0);
+        let (quality, _) = encode(
+            Some(key),
+            "/a.png",
+            source,
+            OutputFormat::Jpeg,
+            image,
+            0.0015,
+            80,
+            |quality| {
+                encodes.set(encodes.get() + 1);
+                let mut data = Vec::new();
+                let (width, height) = (image.width().get(), image.height().get());
+                JpegEncoder::new_with_quality(&mut data, quality).encode(
+                    image.buffer(),
+                    width,
+                    height,
+                    ColorType::Rgb8,
+                )?;
+                Ok(data)
+            },
+        )
+        .unwrap();
+        (encodes.get(), quality)
+    }
+
+    #[test]
+    fn searched_once_per_output() {
+        let buffer = (0..64u32 * 64)
+            .flat_map(|i| {
+                let (x, y) = (i % 64, i / 64);
+                [
+                    (x * 4) as u8,
+                    (y * 4) as u8,
+                    if (x / 8 + y / 8) % 2 == 0 { 40 } else { 220 },
+                ]
+            })
+            .collect();
+        let size = NonZeroU32::new(64).unwrap();
+        let image = fir::Image::from_vec_u8(size, size, buffer, fir::PixelType::U8x3).unwrap();
+
+        let cache = Cache::new(None, None, cache::config_fingerprint(&Cli::default()));
+        let params = Params {
+            width: NonZeroU32::new(64),
+            ..Default::default()
+        };
+        let blurred = Params {
+            blur: Some(2.0),
+            ..params.clone()
+        };
+        let key = cache.key("/auto-quality.png", &params, Some(OutputFormat::Jpeg));
+        let blurred_key = cache.key("/auto-quality.png", &blurred, Some(OutputFormat::Jpeg));
+
+        let (encodes, quality) = search(&key, b"source", &image);
+        assert!(encodes > 1);
+        // Repeated, the picked quality is encoded right away
+        assert_eq!(search(&key, b"source", &image), (1, quality));
+        // Other pixels, or a source changed at the origin
+        assert!(search(&blurred_key, b"source", &image).0 > 1);
+        assert!(search(&key, b"changed source", &image).0 > 1);
+    }
+}
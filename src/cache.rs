@@ -0,0 +1,518 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use bytes::Bytes;
+use lru::LruCache;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{format::OutputFormat, watermark::Watermark, Cli, Params};
+
+/// Derive the cache key of a resized output from the source path, every parameter affecting it and the
+/// `config_fingerprint`, as `<source>/<variant>` hashes so the outputs of a source can be found by `source_key`.
+///
+/// `format` is the format requested or negotiated before the source is known, the fallback picked from
+/// the source itself is deterministic for a given path so it doesn't need to be part of the key.
+fn cache_key(path: &str, params: &Params, format: Option<OutputFormat>, fingerprint: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(fingerprint);
+    hasher.update(format!("{:?}", params.normalized()).as_bytes());
+    hasher.update(format!("{format:?}").as_bytes());
+
+    format!("{}/{}", source_key(path), hex(&hasher.finalize()))
+}
+
+/// Hash of the flags changing the outputs of given parameters, so that the outputs cached on disk by a server
+/// configured differently aren't served
+pub fn config_fingerprint(config: &Cli) -> [u8; 32] {
+    let flags = (
+        config.default_quality,
+        config.jpeg_quality,
+        config.webp_quality,
+        config.avif_quality,
+        config.auto_quality_target,
+        config.jpeg_encoder,
+        config.background,
+        config.default_scale,
+        config.no_upscale,
+        config.watermark.as_ref().map(Watermark::digest),
+        config.watermark_size,
+    );
+    Sha256::digest(format!("{flags:?}").as_bytes()).into()
+}
+
+/// Prefix of the cache keys of the outputs of the source at `path`, before the `/`
+pub fn source_key(path: &str) -> String {
+    hex(&Sha256::digest(path.as_bytes()))
+}
+
+fn hex(digest: &[u8]) -> String {
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Resized outputs cache, looked up in memory first then on disk
+pub struct Cache {
+    memory: Option<MemoryCache>,
+    disk: Option<DiskCache>,
+    /// `config_fingerprint` of the server
+    fingerprint: [u8; 32],
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// Snapshot of the use and contents of the cache since the server started
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Share of the lookups that hit, 0 before any
+    pub hit_rate: f64,
+    pub memory: Option<MemoryStats>,
+    pub disk: Option<DiskStats>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryStats {
+    pub hits: u64,
+    pub entries: usize,
+    pub bytes: usize,
+    pub capacity_bytes: usize,
+    /// Least recently used entries dropped to make room
+    pub evictions: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskStats {
+    /// Entries found in memory first aren't counted
+    pub hits: u64,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+impl Cache {
+    pub fn new(memory: Option<MemoryCache>, disk: Option<DiskCache>, fingerprint: [u8; 32]) -> Self {
+        Self {
+            memory,
+            disk,
+            fingerprint,
+            memory_hits: AtomicU64::new(0),
+            disk_hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Key of the output of `params` for the source at `path`, see `cache_key`
+    pub fn key(&self, path: &str, params: &Params, format: Option<OutputFormat>) -> String {
+        cache_key(path, params, format, &self.fingerprint)
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.memory.is_some() || self.disk.is_some()
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Bytes> {
+        if let Some(data) = self.memory.as_ref().and_then(|memory| memory.get(key)) {
+            self.memory_hits.fetch_add(1, Ordering::Relaxed);
+            return Some(data);
+        }
+
+        let data = match &self.disk {
+            Some(disk) => disk.get(key).await,
+            None => None,
+        };
+        let Some(data) = data else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        self.disk_hits.fetch_add(1, Ordering::Relaxed);
+        if let Some(memory) = &self.memory {
+            memory.put(key, data.clone());
+        }
+
+        Some(data)
+    }
+
+    pub async fn put(&self, key: &str, data: Bytes) {
+        if let Some(memory) = &self.memory {
+            memory.put(key, data.clone());
+        }
+        if let Some(disk) = &self.disk {
+            disk.put(key, &data).await;
+        }
+    }
+
+    pub async fn stats(&self) -> CacheStats {
+        let (memory_hits, disk_hits) = (
+            self.memory_hits.load(Ordering::Relaxed),
+            self.disk_hits.load(Ordering::Relaxed),
+        );
+        let (hits, misses) = (memory_hits + disk_hits, self.misses.load(Ordering::Relaxed));
+        let disk = match &self.disk {
+            Some(disk) => {
+                let (entries, bytes) = disk.size().await;
+                Some(DiskStats {
+                    hits: disk_hits,
+                    entries,
+                    bytes,
+                })
+            }
+            None => None,
+        };
+
+        CacheStats {
+            hits,
+            misses,
+            hit_rate: if hits + misses == 0 {
+                0.0
+            } else {
+                hits as f64 / (hits + misses) as f64
+            },
+            memory: self.memory.as_ref().map(|memory| memory.stats(memory_hits)),
+            disk,
+        }
+    }
+
+    /// Remove every output of the source at `path`, answering how many there were
+    pub async fn purge(&self, path: &str) -> usize {
+        let source = source_key(path);
+        let mut removed = match &self.memory {
+            Some(memory) => memory.remove_prefix(&format!("{source}/")),
+            None => Vec::new(),
+        };
+        if let Some(disk) = &self.disk {
+            removed.extend(disk.remove_dir(&source).await);
+        }
+
+        // Outputs kept in both count once
+        removed.sort_unstable();
+        removed.dedup();
+        removed.len()
+    }
+}
+
+/// Entry of a `MemoryCache`
+pub trait Weigh: Clone {
+    /// Bytes held by the entry
+    fn size(&self) -> usize;
+}
+
+impl Weigh for Bytes {
+    fn size(&self) -> usize {
+        self.len()
+    }
+}
+
+/// LRU cache bounded by the total byte size of the stored entries
+pub struct MemoryCache<T = Bytes> {
+    inner: Mutex<MemoryCacheInner<T>>,
+}
+
+struct MemoryCacheInner<T> {
+    entries: LruCache<String, T>,
+    size: usize,
+    capacity: usize,
+    evictions: u64,
+}
+
+impl<T: Weigh> MemoryCache<T> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inner: Mutex::new(MemoryCacheInner {
+                entries: LruCache::unbounded(),
+                size: 0,
+                capacity,
+                evictions: 0,
+            }),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<T> {
+        self.inner.lock().unwrap().entries.get(key).cloned()
+    }
+
+    pub fn put(&self, key: &str, data: T) {
+        let mut inner = self.inner.lock().unwrap();
+        if data.size() > inner.capacity {
+            return;
+        }
+
+        inner.size += data.size();
+        if let Some(old) = inner.entries.put(key.to_owned(), data) {
+            inner.size -= old.size();
+        }
+
+        while inner.size > inner.capacity {
+            match inner.entries.pop_lru() {
+                Some((_, evicted)) => {
+                    inner.size -= evicted.size();
+                    inner.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    /// Contents of the cache, with the `hits` counted by its owner
+    pub fn stats(&self, hits: u64) -> MemoryStats {
+        let inner = self.inner.lock().unwrap();
+        MemoryStats {
+            hits,
+            entries: inner.entries.len(),
+            bytes: inner.size,
+            capacity_bytes: inner.capacity,
+            evictions: inner.evictions,
+        }
+    }
+
+    /// Remove the entries whose key starts with `prefix`, answering their keys
+    pub fn remove_prefix(&self, prefix: &str) -> Vec<String> {
+        let mut inner = self.inner.lock().unwrap();
+        let keys = inner
+            .entries
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in &keys {
+            if let Some(removed) = inner.entries.pop(key) {
+                inner.size -= removed.size();
+            }
+        }
+
+        keys
+    }
+}
+
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: impl Into<PathBuf>) -> std::io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+
+        Ok(Self { dir })
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Bytes> {
+        match tokio::fs::read(self.dir.join(key)).await {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!(key, "Read cache entry error {err:#}");
+                }
+                None
+            }
+        }
+    }
+
+    pub async fn put(&self, key: &str, data: &[u8]) {
+        // Concurrent misses on the same key each write their own temporary file and atomically rename it
+        // into place, so readers never observe a partially written entry and the last writer wins
+        static COUNTER: AtomicU64 = AtomicU64::new(0);
+        let tmp_path = self.dir.join(format!(
+            "{key}.{}.{}.tmp",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+
+        // Outputs are grouped in a folder per source
+        if let Some(parent) = tmp_path.parent() {
+            if let Err(err) = tokio::fs::create_dir_all(parent).await {
+                tracing::error!(key, "Create cache folder error {err:#}");
+                return;
+            }
+        }
+        let result = match tokio::fs::write(&tmp_path, data).await {
+            Ok(()) => tokio::fs::rename(&tmp_path, self.dir.join(key)).await,
+            Err(err) => Err(err),
+        };
+
+        if let Err(err) = result {
+            tracing::error!(key, "Write cache entry error {err:#}");
+            let _ = tokio::fs::remove_file(&tmp_path).await;
+        }
+    }
+
+    /// Count and total size of the entries, read from the folders of the sources
+    pub async fn size(&self) -> (usize, u64) {
+        let (mut entries, mut bytes) = (0, 0);
+        let Ok(mut sources) = tokio::fs::read_dir(&self.dir).await else {
+            return (entries, bytes);
+        };
+        while let Ok(Some(source)) = sources.next_entry().await {
+            let Ok(mut outputs) = tokio::fs::read_dir(source.path()).await else {
+                continue;
+            };
+            while let Ok(Some(output)) = outputs.next_entry().await {
+                match output.metadata().await {
+                    Ok(metadata) if metadata.is_file() && !output.file_name().to_string_lossy().ends_with(".tmp") => {
+                        entries += 1;
+                        bytes += metadata.len();
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        (entries, bytes)
+    }
+
+    /// Remove the folder of the outputs of `source`, answering their keys
+    pub async fn remove_dir(&self, source: &str) -> Vec<String> {
+        let dir = self.dir.join(source);
+        let mut keys = Vec::new();
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!(source, "Read cache folder error {err:#}");
+                }
+                return keys;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // Temporary files of writes in progress are no entries yet
+            if !name.ends_with(".tmp") {
+                keys.push(format!("{source}/{name}"));
+            }
+        }
+
+        if let Err(err) = tokio::fs::remove_dir_all(&dir).await {
+            tracing::error!(source, "Remove cache folder error {err:#}");
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+    use crate::format::{JpegEncoderKind, Quality};
+
+    fn params(width: u32) -> Params {
+        Params {
+            width: NonZeroU32::new(width),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn cache_key_stable() {
+        let fingerprint = config_fingerprint(&Cli::default());
+        let key = cache_key("/a.jpg", &params(100), Some(OutputFormat::Webp), &fingerprint);
+        assert_eq!(
+            key,
+            cache_key("/a.jpg", &params(100), Some(OutputFormat::Webp), &fingerprint)
+        );
+
+        let (source, variant) = key.split_once('/').unwrap();
+        assert_eq!(source, source_key("/a.jpg"));
+        assert_eq!(variant.len(), 64);
+        assert_eq!(fingerprint, config_fingerprint(&Cli::default()));
+    }
+
+    #[test]
+    fn cache_key_short_names() {
+        let fingerprint = config_fingerprint(&Cli::default());
+        let full = Params {
+            width: NonZeroU32::new(100),
+            height: NonZeroU32::new(50),
+            format: Some(OutputFormat::Png),
+            quality: Some(Quality::Fixed(60)),
+            ..Default::default()
+        };
+        let short = Params {
+            w: NonZeroU32::new(100),
+            h: NonZeroU32::new(50),
+            f: Some(OutputFormat::Png),
+            q: Some(Quality::Fixed(60)),
+            ..Default::default()
+        };
+        let imgix = Params {
+            f: None,
+            fm: Some(OutputFormat::Png),
+            ..short.clone()
+        };
+
+        let download = Params {
+            download: Some(true),
+            ..full.clone()
+        };
+
+        let key = cache_key("/a.jpg", &full, None, &fingerprint);
+        assert_eq!(key, cache_key("/a.jpg", &short, None, &fingerprint));
+        assert_eq!(key, cache_key("/a.jpg", &imgix, None, &fingerprint));
+        // The same bytes, only served as an attachment
+        assert_eq!(key, cache_key("/a.jpg", &download, None, &fingerprint));
+    }
+
+    #[test]
+    fn cache_key_sensitive() {
+        let fingerprint = config_fingerprint(&Cli::default());
+        let key = cache_key("/a.jpg", &params(100), None, &fingerprint);
+        assert_ne!(key, cache_key("/b.jpg", &params(100), None, &fingerprint));
+        assert_ne!(key, cache_key("/a.jpg", &params(101), None, &fingerprint));
+        assert_ne!(
+            key,
+            cache_key("/a.jpg", &params(100), Some(OutputFormat::Jpeg), &fingerprint)
+        );
+
+        let configs: [fn(&mut Cli); 8] = [
+            |config| config.default_quality = 70,
+            |config| config.webp_quality = Some(90),
+            |config| config.auto_quality_target = 0.002,
+            |config| config.jpeg_encoder = JpegEncoderKind::Mozjpeg,
+            |config| config.background = [0, 0, 0],
+            |config| config.default_scale = 0.5,
+            |config| config.no_upscale = true,
+            |config| config.watermark_size = 10.0,
+        ];
+        for configure in configs {
+            let mut config = Cli::default();
+            configure(&mut config);
+            let other = config_fingerprint(&config);
+            assert_ne!(key, cache_key("/a.jpg", &params(100), None, &other));
+        }
+    }
+
+    #[test]
+    fn memory_cache_hit_and_miss() {
+        let cache = MemoryCache::new(1024);
+        assert_eq!(cache.get("a"), None);
+        cache.put("a", Bytes::from_static(b"output"));
+        assert_eq!(cache.get("a"), Some(Bytes::from_static(b"output")));
+        assert_eq!(cache.get("b"), None);
+    }
+
+    #[test]
+    fn memory_cache_evicts_by_size() {
+        let cache = MemoryCache::new(10);
+        cache.put("a", Bytes::from(vec![0; 4]));
+        cache.put("b", Bytes::from(vec![0; 4]));
+        // `a` becomes the most recently used
+        assert!(cache.get("a").is_some());
+        cache.put("c", Bytes::from(vec![0; 4]));
+        assert!(cache.get("a").is_some());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("c").is_some());
+
+        // Larger than the whole cache, never stored
+        cache.put("d", Bytes::from(vec![0; 11]));
+        assert!(cache.get("d").is_none());
+
+        let stats = cache.stats(0);
+        assert_eq!((stats.entries, stats.bytes, stats.evictions), (2, 8, 1));
+    }
+}
@@ -0,0 +1,159 @@
+use std::path::Path;
+
+use axum::http::HeaderMap;
+use axum_server::tls_rustls::RustlsConfig;
+use reqwest::Client;
+
+use crate::{
+    format,
+    source::{FetchError, Source, SourceChain, SourceKind},
+    Cli,
+};
+
+/// Outcome of the `--check` validations, printed one line each
+#[derive(Debug, Default)]
+pub struct Report {
+    problems: usize,
+}
+
+impl Report {
+    fn ok(&self, subject: &str, detail: impl std::fmt::Display) {
+        println!("ok    {subject}: {detail}");
+    }
+
+    fn fail(&mut self, subject: &str, problem: impl std::fmt::Display) {
+        self.problems += 1;
+        println!("FAIL  {subject}: {problem}");
+    }
+
+    pub fn passed(&self) -> bool {
+        self.problems == 0
+    }
+}
+
+/// Report the `problems` of the flags, and check that what the configuration points to is there. Nothing is
+/// written, the cache folder isn't created yet.
+pub async fn run(config: &Cli, problems: &[String], client: &Client, sources: &SourceChain) -> Report {
+    let mut report = Report::default();
+    for problem in problems {
+        report.fail("flags", problem);
+    }
+
+    for folder in config.local_folder.iter().flatten() {
+        match tokio::fs::read_dir(folder).await {
+            Ok(_) => report.ok("local folder", folder),
+            Err(err) => report.fail("local folder", format_args!("{folder}: {err}")),
+        }
+    }
+
+    if let Some(path) = &config.check_path {
+        sample(&mut report, "sample", path, sources).await;
+        // The chain may find the sample before the remote CDN, which gets asked for it alone. A remote CDN without
+        // `--check-path` is one of the `problems`.
+        if let Ok(Some(remote)) = crate::build_source(SourceKind::Remote, config, client) {
+            sample(&mut report, "remote cdn", path, remote.as_ref()).await;
+        }
+    }
+
+    if let Some(dir) = &config.cache_dir {
+        match writable(dir).await {
+            Ok(true) => report.ok("cache dir", dir.display()),
+            Ok(false) => report.ok("cache dir", format_args!("{} is created on start", dir.display())),
+            Err(err) => report.fail("cache dir", format_args!("{}: {err}", dir.display())),
+        }
+    }
+
+    if let Some(path) = &config.fallback_image {
+        match tokio::fs::read(path).await {
+            Ok(bytes) if format::source_content_type(&bytes).is_some() => report.ok("fallback image", path.display()),
+            Ok(_) => report.fail(
+                "fallback image",
+                format_args!("{} isn't a supported image", path.display()),
+            ),
+            Err(err) => report.fail("fallback image", format_args!("{}: {err}", path.display())),
+        }
+    }
+
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        match RustlsConfig::from_pem_file(cert, key).await {
+            Ok(_) => report.ok("tls certificate", cert.display()),
+            Err(err) => report.fail("tls certificate", format_args!("{}: {err}", cert.display())),
+        }
+    }
+
+    report
+}
+
+/// Fetch the sample at `path` from `source`, which must be a supported image
+async fn sample(report: &mut Report, subject: &str, path: &str, source: &dyn Source) {
+    match source.fetch(path, &HeaderMap::new()).await {
+        Ok(fetched) => match format::source_content_type(&fetched.data) {
+            Some(content_type) => report.ok(
+                subject,
+                format_args!("{path} is {content_type}, {} bytes", fetched.data.len()),
+            ),
+            None => report.fail(subject, format_args!("{path} isn't a supported image")),
+        },
+        Err(err) => report.fail(subject, format_args!("{path}: {}", describe(err))),
+    }
+}
+
+fn describe(err: FetchError) -> String {
+    match err {
+        FetchError::NotFound => "not found".to_owned(),
+        FetchError::Forbidden => "host not in 'allowed_hosts'".to_owned(),
+        FetchError::TooLarge => "larger than 'max_source_bytes'".to_owned(),
+        FetchError::Upstream(Some(status)) => format!("origin answered {status}"),
+        FetchError::Upstream(None) => "origin didn't answer".to_owned(),
+        FetchError::UpstreamTimeout => "origin didn't answer in time".to_owned(),
+    }
+}
+
+/// Write and remove a file in `dir`, as the cache would, or else in the closest folder it would be created in.
+/// Whether `dir` exists.
+async fn writable(dir: &Path) -> std::io::Result<bool> {
+    let mut folder = dir;
+    while !tokio::fs::try_exists(folder).await? {
+        match folder.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => folder = parent,
+            _ => {
+                folder = Path::new(".");
+                break;
+            }
+        }
+    }
+
+    let probe = folder.join(".check.tmp");
+    tokio::fs::write(&probe, b"").await?;
+    tokio::fs::remove_file(&probe).await?;
+    Ok(folder == dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_without_writing() {
+        let folder = std::env::temp_dir().join(format!("image-resize-check-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let cache_dir = folder.join("cache").join("outputs");
+        let config = crate::Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--check",
+            "--local-folder",
+            folder.to_str().unwrap(),
+            "--cache-dir",
+            cache_dir.to_str().unwrap(),
+        ]);
+        let problems = ["'tls_cert' requires 'tls_key'".to_owned()];
+        let report = run(&config, &problems, &Client::new(), &SourceChain::new(Vec::new())).await;
+        let created = cache_dir.parent().unwrap().exists();
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(report.problems, 1);
+        assert!(!created);
+    }
+}
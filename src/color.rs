@@ -0,0 +1,72 @@
+use std::str::FromStr;
+
+use serde::{de, Deserialize, Deserializer};
+
+/// RGBA color given as `rrggbb`, `rrggbbaa` (each optionally prefixed by `#`) or a CSS color name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Color(pub [u8; 4]);
+
+impl Color {
+    pub fn rgb(self) -> [u8; 3] {
+        [self.0[0], self.0[1], self.0[2]]
+    }
+
+    pub fn is_opaque(self) -> bool {
+        self.0[3] == u8::MAX
+    }
+}
+
+const NAMED_COLORS: &[(&str, [u8; 4])] = &[
+    ("transparent", [0, 0, 0, 0]),
+    ("black", [0, 0, 0, 255]),
+    ("white", [255, 255, 255, 255]),
+    ("gray", [128, 128, 128, 255]),
+    ("grey", [128, 128, 128, 255]),
+    ("silver", [192, 192, 192, 255]),
+    ("red", [255, 0, 0, 255]),
+    ("maroon", [128, 0, 0, 255]),
+    ("orange", [255, 165, 0, 255]),
+    ("yellow", [255, 255, 0, 255]),
+    ("olive", [128, 128, 0, 255]),
+    ("lime", [0, 255, 0, 255]),
+    ("green", [0, 128, 0, 255]),
+    ("aqua", [0, 255, 255, 255]),
+    ("cyan", [0, 255, 255, 255]),
+    ("teal", [0, 128, 128, 255]),
+    ("blue", [0, 0, 255, 255]),
+    ("navy", [0, 0, 128, 255]),
+    ("fuchsia", [255, 0, 255, 255]),
+    ("magenta", [255, 0, 255, 255]),
+    ("purple", [128, 0, 128, 255]),
+];
+
+impl FromStr for Color {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if let Some((_, color)) = NAMED_COLORS.iter().find(|(name, _)| name.eq_ignore_ascii_case(value)) {
+            return Ok(Color(*color));
+        }
+
+        let hex = value.strip_prefix('#').unwrap_or(value);
+        if !matches!(hex.len(), 6 | 8) || !hex.is_ascii() {
+            return Err(format!(
+                "invalid color `{value}`, expected `rrggbb`, `rrggbbaa` or a color name"
+            ));
+        }
+
+        let mut color = [u8::MAX; 4];
+        for (i, channel) in color.iter_mut().take(hex.len() / 2).enumerate() {
+            *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+                .map_err(|err| format!("invalid color `{value}`: {err}"))?;
+        }
+
+        Ok(Color(color))
+    }
+}
+
+impl<'de> Deserialize<'de> for Color {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
@@ -0,0 +1,187 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use clap::{ArgAction, Command, CommandFactory, FromArgMatches};
+use serde::de::DeserializeOwned;
+
+/// Parse the command line `args` over the TOML file of its `--config` flag, if any.
+///
+/// Flags given on the command line take precedence over the file, flags given in neither take their default value.
+/// The command line is checked as clap always does, with `--help` and errors exiting the process.
+pub fn parse<T>(args: Vec<OsString>) -> Result<T, String>
+where
+    T: CommandFactory + FromArgMatches + DeserializeOwned,
+{
+    let matches = T::command().get_matches_from(&args);
+    let Some(path) = matches.get_one::<PathBuf>("config") else {
+        return T::from_arg_matches(&matches).map_err(|err| err.to_string());
+    };
+
+    let mut config = load::<T>(path)?;
+    let mut given = without_defaults(T::command()).get_matches_from(args);
+    config
+        .update_from_arg_matches_mut(&mut given)
+        .map_err(|err| err.to_string())?;
+    Ok(config)
+}
+
+/// Read the TOML config file, keyed by the long flag names or their aliases, either `max-width` or `max_width`
+pub fn load<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let content = std::fs::read_to_string(path).map_err(|err| format!("Failed to read {}: {err}", path.display()))?;
+    let table = content
+        .parse::<toml::Table>()
+        .map_err(|err| format!("Failed to parse {}: {err}", path.display()))?;
+
+    let table = table
+        .into_iter()
+        .map(|(key, value)| (key.replace('-', "_"), value))
+        .collect::<toml::Table>();
+    toml::Value::Table(table)
+        .try_into()
+        .map_err(|err| format!("Invalid {}: {err}", path.display()))
+}
+
+/// `command` matching the flags given and nothing else, so that updating the values of the file with its matches
+/// leaves the others as they are
+fn without_defaults(command: Command<'static>) -> Command<'static> {
+    let ids = command.get_arguments().map(|arg| arg.get_id()).collect::<Vec<_>>();
+    ids.into_iter().fold(command, |command, id| {
+        command.mut_arg(id, |arg| match arg.get_action() {
+            // Switches are `false` when absent, which would turn off the ones the file turns on
+            ArgAction::SetTrue => arg
+                .action(ArgAction::Set)
+                .min_values(0)
+                .require_equals(true)
+                .default_missing_value("true"),
+            _ => arg.default_values_os(&[]),
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cli;
+
+    fn write_config(name: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("image-resize-{name}-{}.toml", std::process::id()));
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        std::iter::once("image-resize")
+            .chain(args.iter().copied())
+            .map(OsString::from)
+            .collect()
+    }
+
+    #[test]
+    fn file_matches_command_line() {
+        let path = write_config(
+            "round-trip",
+            r#"
+                local-folder = ["/srv/a", "/srv/b"]
+                max_width = 2048
+                no_upscale = true
+                background = "102030"
+                cors_origin = ["*.example.com", "https://example.org"]
+                forward_headers = ["Authorization"]
+                jpeg_encoder = "mozjpeg"
+                host = "127.0.0.1:8080"
+            "#,
+        );
+        let from_file = parse::<Cli>(args(&["--config", path.to_str().unwrap()])).unwrap();
+        let from_args = parse::<Cli>(args(&[
+            "--local-folder=/srv/a,/srv/b",
+            "--max-width=2048",
+            "--no-upscale",
+            "--background=102030",
+            "--cors-origin=*.example.com,https://example.org",
+            "--forward-headers=Authorization",
+            "--jpeg-encoder=mozjpeg",
+            "--bind=127.0.0.1:8080",
+        ]))
+        .unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        for cli in [&from_file, &from_args] {
+            assert_eq!(
+                cli.local_folder.as_deref(),
+                Some(&["/srv/a".to_owned(), "/srv/b".to_owned()][..])
+            );
+            assert_eq!(cli.max_width, 2048);
+            assert!(cli.no_upscale);
+            assert_eq!(cli.background, [0x10, 0x20, 0x30]);
+            assert_eq!(cli.cors_origin.as_ref().map(Vec::len), Some(2));
+            assert_eq!(cli.jpeg_encoder, crate::JpegEncoderKind::Mozjpeg);
+            assert_eq!(cli.forward_headers.as_ref().map(Vec::len), Some(1));
+            assert_eq!(cli.bind.map(|bind| bind.port), Some(Some(8080)));
+            // Left out of both
+            assert_eq!(cli.max_height, 4096);
+            assert_eq!(cli.default_quality, crate::DEFAULT_QUALITY);
+        }
+    }
+
+    #[test]
+    fn command_line_over_file() {
+        let path = write_config(
+            "precedence",
+            r#"
+                local_folder = ["/srv/a"]
+                max_width = 2048
+                max_height = 1024
+                trust_forwarded_for = true
+            "#,
+        );
+        let cli = parse::<Cli>(args(&[
+            "--config",
+            path.to_str().unwrap(),
+            "--max-width",
+            "512",
+            "--debug-timing",
+        ]));
+        std::fs::remove_file(path).unwrap();
+
+        let cli = cli.unwrap();
+        assert_eq!(cli.max_width, 512);
+        assert_eq!(cli.max_height, 1024);
+        // Switches of the file stay on when the command line doesn't give them
+        assert!(cli.trust_forwarded_for);
+        assert!(cli.debug_timing);
+        assert!(!cli.no_cors);
+        assert_eq!(cli.local_folder.as_deref(), Some(&["/srv/a".to_owned()][..]));
+    }
+
+    #[test]
+    fn repeated_flag_array() {
+        let path = write_config(
+            "origin-headers",
+            r#"
+                local_folder = ["/srv/a"]
+                origin_header = ["X-A: 1", "X-B: 2, 3"]
+            "#,
+        );
+        let cli = parse::<Cli>(args(&["--config", path.to_str().unwrap()]));
+        std::fs::remove_file(path).unwrap();
+
+        // One header per element, a comma in a value doesn't split it
+        let headers = cli.unwrap().origin_headers.unwrap();
+        let headers = headers
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.to_str().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(headers, [("x-a", "1"), ("x-b", "2, 3")]);
+    }
+
+    #[test]
+    fn unknown_key() {
+        let path = write_config("unknown", "max_widht = 2048\n");
+        let result = parse::<Cli>(args(&["--config", path.to_str().unwrap()]));
+        std::fs::remove_file(path).unwrap();
+
+        assert!(result.is_err_and(|err| err.contains("max_widht")));
+    }
+}
@@ -0,0 +1,127 @@
+use std::{fmt, str::FromStr};
+
+use axum::http::{
+    header::{self, HeaderName},
+    request::Parts,
+    HeaderValue, Method, Uri,
+};
+use serde::{de, Deserialize, Deserializer};
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Origin allowed to request images from browsers
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsOrigin {
+    /// `*`, any origin
+    Any,
+    /// `scheme://host[:port]`, that exact origin
+    Exact(HeaderValue),
+    /// `*.example.com`, any origin ending with `.example.com`
+    Suffix(String),
+}
+
+impl FromStr for CorsOrigin {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "*" {
+            return Ok(CorsOrigin::Any);
+        }
+        if let Some(suffix) = value.strip_prefix('*') {
+            if !suffix.starts_with('.') || suffix.len() < 2 || suffix.contains(['*', '/']) {
+                return Err(format!("invalid origin suffix `{value}`, expected `*.example.com`"));
+            }
+            return Ok(CorsOrigin::Suffix(suffix.to_owned()));
+        }
+
+        let uri = value
+            .parse::<Uri>()
+            .map_err(|err| format!("invalid origin `{value}`: {err}"))?;
+        if uri.scheme().is_none() || uri.authority().is_none() || !matches!(uri.path(), "" | "/") {
+            return Err(format!("invalid origin `{value}`, expected `scheme://host[:port]`"));
+        }
+
+        // Browsers send origins without trailing slash
+        HeaderValue::from_str(value.trim_end_matches('/'))
+            .map(CorsOrigin::Exact)
+            .map_err(|err| format!("invalid origin `{value}`: {err}"))
+    }
+}
+
+impl<'de> Deserialize<'de> for CorsOrigin {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+impl fmt::Display for CorsOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorsOrigin::Any => f.write_str("*"),
+            CorsOrigin::Exact(origin) => f.write_str(origin.to_str().unwrap_or_default()),
+            CorsOrigin::Suffix(suffix) => write!(f, "*{suffix}"),
+        }
+    }
+}
+
+/// Allow GET requests from the given origins, `*` has to be given alone
+pub fn layer(origins: &[CorsOrigin]) -> Result<CorsLayer, String> {
+    let allow_origin = if origins.contains(&CorsOrigin::Any) {
+        if origins.len() > 1 {
+            return Err("`*` allows any origin and can't be combined with other origins".to_owned());
+        }
+        AllowOrigin::any()
+    } else {
+        let origins = origins.to_vec();
+        AllowOrigin::predicate(move |origin: &HeaderValue, _request_parts: &Parts| {
+            origins.iter().any(|allowed| match allowed {
+                CorsOrigin::Any => true,
+                CorsOrigin::Exact(allowed) => allowed == origin,
+                CorsOrigin::Suffix(suffix) => origin.as_bytes().ends_with(suffix.as_bytes()),
+            })
+        })
+    };
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET])
+        // The CORS layer replaces any `Vary` set by the handler, so keep format and DPR negotiation in the list
+        .vary([
+            header::ORIGIN,
+            header::ACCESS_CONTROL_REQUEST_METHOD,
+            header::ACCESS_CONTROL_REQUEST_HEADERS,
+            header::ACCEPT,
+            HeaderName::from_static("sec-ch-dpr"),
+            HeaderName::from_static("dpr"),
+        ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, http::Request, routing::get, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn vary_keeps_client_hints() {
+        let app = Router::new()
+            .route("/", get(|| async { ([(header::VARY, "Accept, Sec-CH-DPR, DPR")], "") }))
+            .layer(layer(&[CorsOrigin::Any]).unwrap());
+        let request = Request::get("/")
+            .header(header::ORIGIN, "https://example.com")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+
+        let vary: Vec<_> = response
+            .headers()
+            .get_all(header::VARY)
+            .iter()
+            .flat_map(|value| value.to_str().unwrap().split(','))
+            .map(|name| name.trim().to_ascii_lowercase())
+            .collect();
+        for name in ["origin", "accept", "sec-ch-dpr", "dpr"] {
+            assert!(vary.iter().any(|vary| vary == name), "{name} missing from {vary:?}");
+        }
+    }
+}
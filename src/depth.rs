@@ -0,0 +1,49 @@
+use fast_image_resize as fir;
+use serde::Deserialize;
+
+/// Bits per channel of PNG outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum BitDepth {
+    #[serde(rename = "8")]
+    Eight,
+    /// Kept from 16-bit sources, 8-bit ones have nothing to widen
+    #[serde(rename = "16")]
+    Sixteen,
+}
+
+/// Whether the channels of `image` are 16-bit, in native endian as `image` expects them
+pub fn is_16_bit(image: &fir::Image) -> bool {
+    matches!(image.pixel_type(), fir::PixelType::U16x3 | fir::PixelType::U16x4)
+}
+
+pub fn has_alpha(image: &fir::Image) -> bool {
+    matches!(image.pixel_type(), fir::PixelType::U8x4 | fir::PixelType::U16x4)
+}
+
+/// `image` rounded to 8-bit channels, `None` when it already is
+pub fn to_8_bit(image: &fir::Image) -> Option<fir::Image<'static>> {
+    let pixel_type = match image.pixel_type() {
+        fir::PixelType::U16x3 => fir::PixelType::U8x3,
+        fir::PixelType::U16x4 => fir::PixelType::U8x4,
+        _ => return None,
+    };
+    let buffer = values(image.buffer())
+        .into_iter()
+        .map(|value| ((value as u32 * 255 + 32767) / 65535) as u8)
+        .collect();
+
+    Some(fir::Image::from_vec_u8(image.width(), image.height(), buffer, pixel_type).unwrap())
+}
+
+/// 16-bit channels of a native endian buffer
+pub fn values(buffer: &[u8]) -> Vec<u16> {
+    buffer
+        .chunks_exact(2)
+        .map(|value| u16::from_ne_bytes([value[0], value[1]]))
+        .collect()
+}
+
+/// Native endian buffer of 16-bit channels
+pub fn bytes(values: &[u16]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_ne_bytes()).collect()
+}
@@ -0,0 +1,198 @@
+use std::num::NonZeroU32;
+
+use fast_image_resize as fir;
+use image::{imageops, DynamicImage, ImageBuffer, Rgb, Rgba};
+use serde::Deserialize;
+
+use crate::depth;
+
+/// Largest `blur` sigma, the cost of the blur grows with it
+pub const MAX_BLUR: f32 = 50.0;
+/// Largest `sharpen` amount, stronger ones mostly add halos
+pub const MAX_SHARPEN: f32 = 3.0;
+/// Radius of the unsharp mask, fine enough to bring back the details lost by downscaling
+const SHARPEN_SIGMA: f32 = 1.0;
+
+/// Transforms applied to the resized pixels, in order
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Effects {
+    /// Gaussian blur sigma, 0 to `MAX_BLUR`
+    pub blur: f32,
+    /// Unsharp mask amount, 0 to `MAX_SHARPEN`
+    pub sharpen: f32,
+    pub color: Option<ColorEffect>,
+    /// Applied by `orient`, on top of the EXIF orientation already applied when decoding
+    pub rotate: Option<Rotation>,
+    pub flip: Option<Flip>,
+}
+
+/// Clockwise rotation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Rotation {
+    #[serde(rename = "90")]
+    Rotate90,
+    #[serde(rename = "180")]
+    Rotate180,
+    #[serde(rename = "270")]
+    Rotate270,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Flip {
+    /// Mirror left to right
+    #[serde(rename = "h")]
+    Horizontal,
+    /// Mirror top to bottom
+    #[serde(rename = "v")]
+    Vertical,
+}
+
+/// Transform of the colors of every pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorEffect {
+    /// Luma with the Rec. 709 weights
+    Grayscale,
+    /// Grayscale warmed up to brown tones
+    Sepia,
+    /// Negative
+    Invert,
+}
+
+impl ColorEffect {
+    fn apply(self, [r, g, b]: [f32; 3]) -> [f32; 3] {
+        match self {
+            ColorEffect::Grayscale => [0.2126 * r + 0.7152 * g + 0.0722 * b; 3],
+            ColorEffect::Sepia => [
+                0.393 * r + 0.769 * g + 0.189 * b,
+                0.349 * r + 0.686 * g + 0.168 * b,
+                0.272 * r + 0.534 * g + 0.131 * b,
+            ],
+            ColorEffect::Invert => [255.0 - r, 255.0 - g, 255.0 - b],
+        }
+    }
+}
+
+impl Effects {
+    pub fn apply(&self, mut image: fir::Image<'static>) -> fir::Image<'static> {
+        if self.blur > 0.0 {
+            let (width, height, pixel_type) = (image.width(), image.height(), image.pixel_type());
+            image = fir::Image::from_vec_u8(width, height, blur_buffer(&image, self.blur), pixel_type).unwrap();
+        }
+        if self.sharpen > 0.0 {
+            image = sharpen(image, self.sharpen);
+        }
+        if let Some(effect) = self.color {
+            image = transform_colors(image, effect);
+        }
+
+        image
+    }
+
+    /// Whether `apply` changes anything, rather than leaving the pixels as they are
+    pub fn changes_pixels(&self) -> bool {
+        self.blur > 0.0 || self.sharpen > 0.0 || self.color.is_some()
+    }
+
+    /// Rotate, then flip
+    pub fn orient(&self, image: fir::Image<'static>) -> fir::Image<'static> {
+        if self.rotate.is_none() && self.flip.is_none() {
+            return image;
+        }
+
+        let mut image = to_dynamic(image);
+        image = match self.rotate {
+            Some(Rotation::Rotate90) => image.rotate90(),
+            Some(Rotation::Rotate180) => image.rotate180(),
+            Some(Rotation::Rotate270) => image.rotate270(),
+            None => image,
+        };
+        image = match self.flip {
+            Some(Flip::Horizontal) => image.fliph(),
+            Some(Flip::Vertical) => image.flipv(),
+            None => image,
+        };
+
+        from_dynamic(image)
+    }
+}
+
+/// Add `amount` times the difference with a blurred copy to the color channels, leaving alpha untouched
+fn sharpen(image: fir::Image<'static>, amount: f32) -> fir::Image<'static> {
+    let blurred = blur_buffer(&image, SHARPEN_SIGMA);
+    let (width, height, pixel_type) = (image.width(), image.height(), image.pixel_type());
+    let has_alpha = pixel_type == fir::PixelType::U8x4;
+
+    let mut buffer = image.into_vec();
+    for (i, (value, &blurred)) in buffer.iter_mut().zip(&blurred).enumerate() {
+        if has_alpha && i % 4 == 3 {
+            continue;
+        }
+
+        let sharpened = *value as f32 + amount * (*value as f32 - blurred as f32);
+        *value = sharpened.round().clamp(0.0, 255.0) as u8;
+    }
+
+    fir::Image::from_vec_u8(width, height, buffer, pixel_type).unwrap()
+}
+
+fn transform_colors(image: fir::Image<'static>, effect: ColorEffect) -> fir::Image<'static> {
+    let (width, height, pixel_type) = (image.width(), image.height(), image.pixel_type());
+    let channels = if pixel_type == fir::PixelType::U8x4 { 4 } else { 3 };
+
+    let mut buffer = image.into_vec();
+    for pixel in buffer.chunks_exact_mut(channels) {
+        let rgb = effect.apply([pixel[0] as f32, pixel[1] as f32, pixel[2] as f32]);
+        for (channel, value) in pixel.iter_mut().zip(rgb) {
+            *channel = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+
+    fir::Image::from_vec_u8(width, height, buffer, pixel_type).unwrap()
+}
+
+fn to_dynamic(image: fir::Image<'static>) -> DynamicImage {
+    let (width, height) = (image.width().get(), image.height().get());
+    match image.pixel_type() {
+        fir::PixelType::U8x4 => {
+            DynamicImage::ImageRgba8(ImageBuffer::from_raw(width, height, image.into_vec()).unwrap())
+        }
+        fir::PixelType::U16x4 => {
+            DynamicImage::ImageRgba16(ImageBuffer::from_raw(width, height, depth::values(image.buffer())).unwrap())
+        }
+        fir::PixelType::U16x3 => {
+            DynamicImage::ImageRgb16(ImageBuffer::from_raw(width, height, depth::values(image.buffer())).unwrap())
+        }
+        _ => DynamicImage::ImageRgb8(ImageBuffer::from_raw(width, height, image.into_vec()).unwrap()),
+    }
+}
+
+fn from_dynamic(image: DynamicImage) -> fir::Image<'static> {
+    let (width, height) = (image.width(), image.height());
+    let pixel_type = match image.color() {
+        image::ColorType::Rgba16 => fir::PixelType::U16x4,
+        image::ColorType::Rgb16 => fir::PixelType::U16x3,
+        color if color.has_alpha() => fir::PixelType::U8x4,
+        _ => fir::PixelType::U8x3,
+    };
+
+    fir::Image::from_vec_u8(
+        NonZeroU32::new(width).unwrap(),
+        NonZeroU32::new(height).unwrap(),
+        image.into_bytes(),
+        pixel_type,
+    )
+    .unwrap()
+}
+
+/// Gaussian blur of the RGB or RGBA pixels of `image`
+fn blur_buffer(image: &fir::Image, sigma: f32) -> Vec<u8> {
+    let (width, height) = (image.width().get(), image.height().get());
+    if image.pixel_type() == fir::PixelType::U8x4 {
+        let buffer = ImageBuffer::<Rgba<u8>, _>::from_raw(width, height, image.buffer()).unwrap();
+        imageops::blur(&buffer, sigma).into_raw()
+    } else {
+        let buffer = ImageBuffer::<Rgb<u8>, _>::from_raw(width, height, image.buffer()).unwrap();
+        imageops::blur(&buffer, sigma).into_raw()
+    }
+}
@@ -0,0 +1,660 @@
+use std::str::FromStr;
+
+use axum::http::{header, HeaderMap};
+use fast_image_resize as fir;
+use image::{
+    codecs::{
+        gif::{GifEncoder, Repeat},
+        jpeg::JpegEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+    },
+    error::{EncodingError, ImageFormatHint},
+    Delay, Frame, ImageEncoder, ImageError, ImageFormat, RgbaImage,
+};
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{animation::Animation, svg};
+
+/// Same as the default quality of `JpegEncoder::new`
+pub const DEFAULT_QUALITY: u8 = 75;
+/// GIF color quantization speed from 1 (slowest, best palette) to 30, `GifEncoder::new` defaults to a very slow 1
+const GIF_SPEED: i32 = 10;
+/// AVIF encoder speed from 1 (slowest, smallest) to 10 (fastest), a middle ground for on the fly encoding
+pub const DEFAULT_AVIF_SPEED: u8 = 6;
+/// Threads of an AVIF encode, fixed rather than following the core count since rav1e cuts the image in as many tiles
+/// and the same output would get different bytes from one machine to another
+#[cfg(feature = "avif")]
+const AVIF_THREADS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[serde(alias = "jpg")]
+    #[clap(alias = "jpg")]
+    Jpeg,
+    Png,
+    Webp,
+    Avif,
+    Gif,
+}
+
+impl OutputFormat {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Gif => "image/gif",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Gif => "gif",
+        }
+    }
+
+    pub fn from_image_format(format: ImageFormat) -> Option<Self> {
+        match format {
+            ImageFormat::Jpeg => Some(OutputFormat::Jpeg),
+            ImageFormat::Png => Some(OutputFormat::Png),
+            ImageFormat::WebP => Some(OutputFormat::Webp),
+            ImageFormat::Avif => Some(OutputFormat::Avif),
+            ImageFormat::Gif => Some(OutputFormat::Gif),
+            _ => None,
+        }
+    }
+
+    /// Format of an encoded output, `image` only recognizes AVIF files with a 28 bytes `ftyp` box
+    pub fn guess(data: &[u8]) -> Option<Self> {
+        if data.get(4..12) == Some(b"ftypavif") {
+            return Some(OutputFormat::Avif);
+        }
+
+        image::guess_format(data).ok().and_then(Self::from_image_format)
+    }
+
+    pub fn supports_alpha(self) -> bool {
+        !matches!(self, OutputFormat::Jpeg)
+    }
+
+    /// Whether the encoder of the format is compiled in, AVIF needs the `avif` feature
+    pub fn is_available(self) -> bool {
+        match self {
+            OutputFormat::Avif => cfg!(feature = "avif"),
+            OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::Webp | OutputFormat::Gif => true,
+        }
+    }
+}
+
+/// Formats picked through `Accept` negotiation, in order of preference
+const NEGOTIATED_FORMATS: &[OutputFormat] = &[OutputFormat::Avif, OutputFormat::Webp];
+
+pub fn negotiate_format(headers: &HeaderMap) -> Option<OutputFormat> {
+    let accept = headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|media_range| {
+            let mut parts = media_range.split(';').map(str::trim);
+            let media_type = parts.next()?;
+            let rejected = parts.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q <= 0.0)
+            });
+
+            (!rejected).then_some(media_type)
+        })
+        .collect::<Vec<_>>();
+
+    NEGOTIATED_FORMATS
+        .iter()
+        .copied()
+        .filter(|format| format.is_available())
+        .find(|format| {
+            accept
+                .iter()
+                .any(|media_type| media_type.eq_ignore_ascii_case(format.content_type()))
+        })
+}
+
+/// Media type of a source served as is, `None` when it isn't a known image format
+pub fn source_content_type(bytes: &[u8]) -> Option<&'static str> {
+    if svg::is_svg(bytes) {
+        return Some("image/svg+xml");
+    }
+
+    image::guess_format(bytes).ok().map(|format| format.to_mime_type())
+}
+
+/// PNG deflate level, `fast` by default since the gain of `best` rarely pays for its encoding time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngCompression {
+    #[default]
+    Fast,
+    Default,
+    Best,
+    Huffman,
+    Rle,
+}
+
+/// PNG scanline filter strategy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PngFilter {
+    None,
+    Sub,
+    Up,
+    Avg,
+    Paeth,
+    #[default]
+    Adaptive,
+}
+
+/// Requested quality of JPEG, WebP and AVIF outputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quality {
+    /// From 1 to 100
+    Fixed(u8),
+    /// The lowest quality keeping the output close enough to the image, see `auto_quality`
+    Auto,
+}
+
+impl FromStr for Quality {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        if value == "auto" {
+            return Ok(Quality::Auto);
+        }
+
+        value
+            .parse()
+            .map(Quality::Fixed)
+            .map_err(|_| format!("invalid quality `{value}`, expected 1-100 or `auto`"))
+    }
+}
+
+impl<'de> Deserialize<'de> for Quality {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct EncodeOptions {
+    pub quality: u8,
+    pub png_compression: PngCompression,
+    pub png_filter: PngFilter,
+    #[cfg_attr(not(feature = "avif"), allow(dead_code))]
+    pub avif_speed: u8,
+    /// Progressive JPEG scans instead of baseline
+    pub progressive: bool,
+    pub jpeg_encoder: JpegEncoderKind,
+    /// JPEG chroma subsampling, only 4:4:4 baseline JPEG is left to the builtin encoder
+    pub subsampling: ChromaSubsampling,
+    /// Lossless WebP, `quality` is then the compression effort
+    pub lossless: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+pub enum ChromaSubsampling {
+    /// Full resolution chroma, no color fringing around sharp edges
+    #[serde(rename = "444")]
+    Chroma444,
+    /// Half horizontal chroma resolution
+    #[serde(rename = "422")]
+    Chroma422,
+    /// Half horizontal and vertical chroma resolution, the smallest
+    #[default]
+    #[serde(rename = "420")]
+    Chroma420,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum JpegEncoderKind {
+    /// `image`'s encoder for 4:4:4 baseline JPEG, libjpeg for subsampled or progressive JPEG
+    Builtin,
+    /// mozjpeg with trellis quantization and optimized Huffman tables, smaller but slower
+    Mozjpeg,
+}
+
+pub fn encode(format: OutputFormat, options: &EncodeOptions, image: &fir::Image) -> image::ImageResult<Vec<u8>> {
+    let (width, height) = (image.width().get(), image.height().get());
+    let (color_type, layout) = match image.pixel_type() {
+        fir::PixelType::U8x4 => (image::ColorType::Rgba8, webp::PixelLayout::Rgba),
+        // Only PNG is encoded from 16-bit pixels
+        fir::PixelType::U16x4 => (image::ColorType::Rgba16, webp::PixelLayout::Rgba),
+        fir::PixelType::U16x3 => (image::ColorType::Rgb16, webp::PixelLayout::Rgb),
+        _ => (image::ColorType::Rgb8, webp::PixelLayout::Rgb),
+    };
+
+    let mut buf = Vec::new();
+    match format {
+        OutputFormat::Jpeg
+            if options.jpeg_encoder == JpegEncoderKind::Mozjpeg
+                || options.progressive
+                || options.subsampling != ChromaSubsampling::Chroma444 =>
+        {
+            buf = encode_libjpeg(image, options)?
+        }
+        OutputFormat::Jpeg => JpegEncoder::new_with_quality(&mut buf, options.quality).write_image(
+            image.buffer(),
+            width,
+            height,
+            color_type,
+        )?,
+        OutputFormat::Png => {
+            let compression = match options.png_compression {
+                PngCompression::Fast => CompressionType::Fast,
+                PngCompression::Default => CompressionType::Default,
+                PngCompression::Best => CompressionType::Best,
+                // Dropped by `image` which encodes them as `fast` anyway, still accepted for existing URLs
+                PngCompression::Huffman | PngCompression::Rle => CompressionType::Fast,
+            };
+            let filter = match options.png_filter {
+                PngFilter::None => FilterType::NoFilter,
+                PngFilter::Sub => FilterType::Sub,
+                PngFilter::Up => FilterType::Up,
+                PngFilter::Avg => FilterType::Avg,
+                PngFilter::Paeth => FilterType::Paeth,
+                PngFilter::Adaptive => FilterType::Adaptive,
+            };
+
+            PngEncoder::new_with_quality(&mut buf, compression, filter).write_image(
+                image.buffer(),
+                width,
+                height,
+                color_type,
+            )?
+        }
+        OutputFormat::Webp if options.lossless => {
+            let config = lossless_webp_config(options.quality);
+            let encoded = webp::Encoder::new(image.buffer(), layout, width, height)
+                .encode_advanced(&config)
+                .map_err(|err| {
+                    ImageError::Encoding(EncodingError::new(
+                        ImageFormatHint::Exact(ImageFormat::WebP),
+                        format!("{err:?}"),
+                    ))
+                })?;
+            buf.extend_from_slice(&encoded);
+        }
+        OutputFormat::Webp => buf.extend_from_slice(
+            &webp::Encoder::new(image.buffer(), layout, width, height).encode(options.quality as f32),
+        ),
+        OutputFormat::Gif => {
+            GifEncoder::new_with_speed(&mut buf, GIF_SPEED).encode(image.buffer(), width, height, color_type)?
+        }
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => buf = encode_avif(image, options, width, height)?,
+        // Only reached when `is_available` wasn't checked
+        #[cfg(not(feature = "avif"))]
+        OutputFormat::Avif => {
+            return Err(ImageError::Unsupported(
+                image::error::UnsupportedError::from_format_and_kind(
+                    ImageFormatHint::Exact(ImageFormat::Avif),
+                    image::error::UnsupportedErrorKind::Format(ImageFormatHint::Exact(ImageFormat::Avif)),
+                ),
+            ))
+        }
+    }
+
+    Ok(buf)
+}
+
+#[cfg(feature = "avif")]
+fn encode_avif(image: &fir::Image, options: &EncodeOptions, width: u32, height: u32) -> Result<Vec<u8>, ImageError> {
+    let encoder = ravif::Encoder::new()
+        .with_quality(options.quality as f32)
+        .with_alpha_quality(options.quality as f32)
+        .with_speed(options.avif_speed)
+        .with_num_threads(Some(AVIF_THREADS));
+    let (width, height) = (width as usize, height as usize);
+    let result = match image.pixel_type() {
+        fir::PixelType::U8x4 => {
+            let pixels = image
+                .buffer()
+                .chunks_exact(4)
+                .map(|pixel| ravif::RGBA8::new(pixel[0], pixel[1], pixel[2], pixel[3]))
+                .collect::<Vec<_>>();
+            encoder.encode_rgba(ravif::Img::new(&pixels[..], width, height))
+        }
+        _ => {
+            let pixels = image
+                .buffer()
+                .chunks_exact(3)
+                .map(|pixel| ravif::RGB8::new(pixel[0], pixel[1], pixel[2]))
+                .collect::<Vec<_>>();
+            encoder.encode_rgb(ravif::Img::new(&pixels[..], width, height))
+        }
+    };
+
+    result
+        .map(|encoded| encoded.avif_file)
+        .map_err(|err| ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Avif), err)))
+}
+
+/// Encode an animation as an animated GIF or WebP, the only formats able to store one. WebP frames are lossless
+/// when `lossless`
+pub fn encode_animation(
+    format: OutputFormat,
+    quality: u8,
+    lossless: bool,
+    animation: &Animation,
+) -> image::ImageResult<Vec<u8>> {
+    let (width, height) = (animation.width().get(), animation.height().get());
+    let mut buf = Vec::new();
+    match format {
+        OutputFormat::Gif => {
+            let mut encoder = GifEncoder::new_with_speed(&mut buf, GIF_SPEED);
+            match animation.loop_count {
+                0 => encoder.set_repeat(Repeat::Infinite)?,
+                1 => {}
+                plays => encoder.set_repeat(Repeat::Finite(plays - 1))?,
+            }
+
+            encoder.encode_frames(animation.frames.iter().map(|frame| {
+                let buffer = RgbaImage::from_raw(width, height, frame.image.buffer().to_vec()).unwrap();
+                Frame::from_parts(buffer, 0, 0, Delay::from_numer_denom_ms(frame.delay_ms, 1))
+            }))?;
+        }
+        OutputFormat::Webp => {
+            let config = if lossless {
+                lossless_webp_config(quality)
+            } else {
+                let mut config = webp::WebPConfig::new().unwrap();
+                config.quality = quality as f32;
+                config
+            };
+
+            let mut encoder = webp::AnimEncoder::new(width, height, &config);
+            encoder.set_loop_count(animation.loop_count as i32);
+            let mut timestamp = 0;
+            for frame in &animation.frames {
+                encoder.add_frame(webp::AnimFrame::from_rgba(
+                    frame.image.buffer(),
+                    width,
+                    height,
+                    timestamp,
+                ));
+                timestamp += frame.delay_ms as i32;
+            }
+
+            let encoded = encoder.try_encode().map_err(|err| {
+                ImageError::Encoding(EncodingError::new(
+                    ImageFormatHint::Exact(ImageFormat::WebP),
+                    format!("{err:?}"),
+                ))
+            })?;
+            buf.extend_from_slice(&encoded);
+            if let Some(last) = animation.frames.last() {
+                set_last_webp_frame_duration(&mut buf, last.delay_ms);
+            }
+        }
+        _ => {
+            return Err(ImageError::Encoding(EncodingError::new(
+                ImageFormatHint::Unknown,
+                format!("{format:?} doesn't support animations"),
+            )))
+        }
+    }
+
+    Ok(buf)
+}
+
+/// Lossless WebP where `quality` is the compression effort, keeping the colors of fully transparent pixels too
+fn lossless_webp_config(quality: u8) -> webp::WebPConfig {
+    let mut config = webp::WebPConfig::new().unwrap();
+    config.lossless = 1;
+    config.exact = 1;
+    config.quality = quality as f32;
+    config
+}
+
+/// `AnimEncoder` ends the animation without a timestamp, letting libwebp guess how long the last frame lasts
+fn set_last_webp_frame_duration(data: &mut [u8], duration_ms: u32) {
+    let mut last_frame = None;
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes(data[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        if &data[offset..offset + 4] == b"ANMF" && size >= 16 {
+            last_frame = Some(offset);
+        }
+        offset += 8 + size + size % 2;
+    }
+
+    // The duration is the 24 bits following the frame position and size
+    if let Some(offset) = last_frame {
+        data[offset + 20..offset + 23].copy_from_slice(&duration_ms.min(0xFF_FFFF).to_le_bytes()[..3]);
+    }
+}
+
+/// Encode with mozjpeg, or with its plain libjpeg defaults for what the builtin encoder can't do: progressive JPEG and
+/// chroma subsampling
+fn encode_libjpeg(image: &fir::Image, options: &EncodeOptions) -> image::ImageResult<Vec<u8>> {
+    // Errors of libjpeg unwind through the `mozjpeg` calls
+    std::panic::catch_unwind(|| {
+        let mut compress = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        match options.jpeg_encoder {
+            JpegEncoderKind::Builtin => {
+                compress.set_fastest_defaults();
+                if options.progressive {
+                    compress.set_progressive_mode();
+                }
+            }
+            // Progressive is part of mozjpeg defaults, without a scan script a single baseline scan is written
+            JpegEncoderKind::Mozjpeg if !options.progressive => compress.set_optimize_scans(false),
+            JpegEncoderKind::Mozjpeg => {}
+        }
+        compress.set_size(image.width().get() as usize, image.height().get() as usize);
+        compress.set_quality(options.quality as f32);
+        match options.subsampling {
+            ChromaSubsampling::Chroma444 => compress.set_chroma_sampling_pixel_sizes((1, 1), (1, 1)),
+            ChromaSubsampling::Chroma422 => compress.set_chroma_sampling_pixel_sizes((2, 1), (2, 1)),
+            ChromaSubsampling::Chroma420 => compress.set_chroma_sampling_pixel_sizes((2, 2), (2, 2)),
+        }
+
+        let mut compress = compress.start_compress(Vec::new())?;
+        compress.write_scanlines(image.buffer())?;
+        compress.finish()
+    })
+    .unwrap_or_else(|_| Err(std::io::Error::other("libjpeg error")))
+    .map_err(|err| ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Jpeg), err)))
+}
+
+/// Composite an RGBA image over an opaque background, producing an RGB image
+pub fn flatten_alpha(image: &fir::Image, background: [u8; 3]) -> fir::Image<'static> {
+    let buffer = image
+        .buffer()
+        .chunks_exact(4)
+        .flat_map(|pixel| {
+            let alpha = pixel[3] as u32;
+            let mut rgb = [0; 3];
+            for (channel, (&value, &bg)) in rgb.iter_mut().zip(pixel.iter().zip(&background)) {
+                *channel = ((value as u32 * alpha + bg as u32 * (255 - alpha) + 127) / 255) as u8;
+            }
+            rgb
+        })
+        .collect();
+
+    fir::Image::from_vec_u8(image.width(), image.height(), buffer, fir::PixelType::U8x3).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroU32;
+
+    use super::*;
+    use crate::animation::AnimationFrame;
+
+    fn options() -> EncodeOptions {
+        EncodeOptions {
+            quality: DEFAULT_QUALITY,
+            png_compression: PngCompression::default(),
+            png_filter: PngFilter::default(),
+            avif_speed: DEFAULT_AVIF_SPEED,
+            progressive: false,
+            jpeg_encoder: JpegEncoderKind::Builtin,
+            subsampling: ChromaSubsampling::default(),
+            lossless: false,
+        }
+    }
+
+    /// Gradient with sharp edges, so that the encoders have something to work with
+    fn image(width: u32, height: u32) -> fir::Image<'static> {
+        let buffer = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .flat_map(|(x, y)| {
+                [
+                    (x * 255 / width) as u8,
+                    (y * 255 / height) as u8,
+                    if (x / 4 + y / 4) % 2 == 0 { 0 } else { 255 },
+                ]
+            })
+            .collect();
+        fir::Image::from_vec_u8(
+            NonZeroU32::new(width).unwrap(),
+            NonZeroU32::new(height).unwrap(),
+            buffer,
+            fir::PixelType::U8x3,
+        )
+        .unwrap()
+    }
+
+    /// Horizontal and vertical sampling factors of the luma component, from the start of frame segment
+    fn luma_sampling(jpeg: &[u8]) -> u8 {
+        let sof = jpeg
+            .windows(2)
+            .position(|marker| marker == [0xFF, 0xC0] || marker == [0xFF, 0xC2])
+            .unwrap();
+        // Marker, length, precision, height, width, component count, then the id and sampling of the first component
+        jpeg[sof + 11]
+    }
+
+    #[test]
+    fn jpeg_subsampling() {
+        let image = image(32, 32);
+        for (subsampling, sampling) in [
+            (None, 0x22),
+            (Some(ChromaSubsampling::Chroma420), 0x22),
+            (Some(ChromaSubsampling::Chroma422), 0x21),
+            (Some(ChromaSubsampling::Chroma444), 0x11),
+        ] {
+            for jpeg_encoder in [JpegEncoderKind::Builtin, JpegEncoderKind::Mozjpeg] {
+                let options = EncodeOptions {
+                    jpeg_encoder,
+                    subsampling: subsampling.unwrap_or_default(),
+                    ..options()
+                };
+                let jpeg = encode(OutputFormat::Jpeg, &options, &image).unwrap();
+                assert_eq!(luma_sampling(&jpeg), sampling, "{subsampling:?} with {jpeg_encoder:?}");
+            }
+        }
+    }
+
+    fn accept(value: &str) -> HeaderMap {
+        HeaderMap::from_iter([(header::ACCEPT, value.parse().unwrap())])
+    }
+
+    #[test]
+    fn negotiate_preference() {
+        let modern = if cfg!(feature = "avif") {
+            OutputFormat::Avif
+        } else {
+            OutputFormat::Webp
+        };
+        assert_eq!(negotiate_format(&accept("image/avif,image/webp,*/*")), Some(modern));
+        assert_eq!(negotiate_format(&accept("image/webp, image/AVIF;q=0.9")), Some(modern));
+        assert_eq!(
+            negotiate_format(&accept("image/avif;q=0, image/webp")),
+            Some(OutputFormat::Webp)
+        );
+        assert_eq!(negotiate_format(&accept("image/webp;q=0")), None);
+        assert_eq!(negotiate_format(&accept("image/*,*/*;q=0.8")), None);
+        assert_eq!(negotiate_format(&HeaderMap::new()), None);
+    }
+
+    /// Run with `cargo test --no-default-features`
+    #[cfg(not(feature = "avif"))]
+    #[test]
+    fn negotiate_without_avif() {
+        assert!(!OutputFormat::Avif.is_available());
+        assert_eq!(
+            negotiate_format(&accept("image/avif,image/webp")),
+            Some(OutputFormat::Webp)
+        );
+        // Left to the format of the source
+        assert_eq!(negotiate_format(&accept("image/avif,image/*")), None);
+    }
+
+    #[test]
+    fn encode_reproducible() {
+        let image = image(32, 32);
+        let jpeg = |jpeg_encoder, progressive| EncodeOptions {
+            jpeg_encoder,
+            progressive,
+            ..options()
+        };
+        let encodes = [
+            (OutputFormat::Avif, options()),
+            (OutputFormat::Jpeg, jpeg(JpegEncoderKind::Builtin, false)),
+            (OutputFormat::Jpeg, jpeg(JpegEncoderKind::Builtin, true)),
+            (OutputFormat::Jpeg, jpeg(JpegEncoderKind::Mozjpeg, false)),
+            (OutputFormat::Png, options()),
+            (OutputFormat::Webp, options()),
+            (
+                OutputFormat::Webp,
+                EncodeOptions {
+                    lossless: true,
+                    ..options()
+                },
+            ),
+            (OutputFormat::Gif, options()),
+        ];
+
+        for (format, options) in encodes.iter().filter(|(format, _)| format.is_available()) {
+            let first = encode(*format, options, &image).unwrap();
+            let second = encode(*format, options, &image).unwrap();
+            assert!(first == second, "{format:?} with {options:?}");
+        }
+    }
+
+    #[test]
+    fn encode_animation_reproducible() {
+        let frame = |shift: u8| AnimationFrame {
+            image: fir::Image::from_vec_u8(
+                NonZeroU32::new(8).unwrap(),
+                NonZeroU32::new(8).unwrap(),
+                (0..8 * 8 * 4).map(|i| (i as u8).wrapping_add(shift) | 0x03).collect(),
+                fir::PixelType::U8x4,
+            )
+            .unwrap(),
+            delay_ms: 100,
+        };
+        let animation = Animation {
+            frames: vec![frame(0), frame(64)],
+            loop_count: 0,
+        };
+
+        for (format, lossless) in [
+            (OutputFormat::Gif, false),
+            (OutputFormat::Webp, false),
+            (OutputFormat::Webp, true),
+        ] {
+            let first = encode_animation(format, DEFAULT_QUALITY, lossless, &animation).unwrap();
+            let second = encode_animation(format, DEFAULT_QUALITY, lossless, &animation).unwrap();
+            assert!(first == second, "{format:?} lossless {lossless}");
+        }
+    }
+}
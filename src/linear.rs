@@ -0,0 +1,96 @@
+use std::sync::LazyLock;
+
+use fast_image_resize as fir;
+
+use crate::depth;
+
+/// Linear light of the 8-bit sRGB values
+static FROM_SRGB_8: LazyLock<Vec<u16>> =
+    LazyLock::new(|| (0..=u8::MAX).map(|value| to_linear(value as f32 / 255.0)).collect());
+/// Linear light of the 16-bit sRGB values
+static FROM_SRGB_16: LazyLock<Vec<u16>> =
+    LazyLock::new(|| (0..=u16::MAX).map(|value| to_linear(value as f32 / 65535.0)).collect());
+/// sRGB values of the linear light, in 16 bits
+static TO_SRGB: LazyLock<Vec<u16>> = LazyLock::new(|| {
+    (0..=u16::MAX)
+        .map(|value| {
+            let value = value as f32 / 65535.0;
+            let srgb = if value <= 0.003_130_8 {
+                value * 12.92
+            } else {
+                1.055 * value.powf(1.0 / 2.4) - 0.055
+            };
+            (srgb * 65535.0).round() as u16
+        })
+        .collect()
+});
+
+fn to_linear(srgb: f32) -> u16 {
+    let linear = if srgb <= 0.040_45 {
+        srgb / 12.92
+    } else {
+        ((srgb + 0.055) / 1.055).powf(2.4)
+    };
+    (linear * 65535.0).round() as u16
+}
+
+/// 16-bit linear light of the `crop` of `image`
+pub fn linearize(image: &fir::Image, crop: Option<fir::CropBox>) -> fir::Image<'static> {
+    let crop = crop.unwrap_or(fir::CropBox {
+        left: 0,
+        top: 0,
+        width: image.width(),
+        height: image.height(),
+    });
+    let (channels, pixel_type) = if depth::has_alpha(image) {
+        (4, fir::PixelType::U16x4)
+    } else {
+        (3, fir::PixelType::U16x3)
+    };
+    let deep = depth::is_16_bit(image);
+    let channel_len = if deep { 2 } else { 1 };
+    let row_len = image.width().get() as usize * channels * channel_len;
+
+    let mut values = Vec::with_capacity(crop.width.get() as usize * crop.height.get() as usize * channels);
+    for row in image
+        .buffer()
+        .chunks_exact(row_len)
+        .skip(crop.top as usize)
+        .take(crop.height.get() as usize)
+    {
+        let start = crop.left as usize * channels * channel_len;
+        let row = &row[start..start + crop.width.get() as usize * channels * channel_len];
+        if deep {
+            for pixel in depth::values(row).chunks_exact(channels) {
+                values.extend(pixel[..3].iter().map(|&value| FROM_SRGB_16[value as usize]));
+                values.extend(&pixel[3..]);
+            }
+        } else {
+            for pixel in row.chunks_exact(channels) {
+                values.extend(pixel[..3].iter().map(|&value| FROM_SRGB_8[value as usize]));
+                values.extend(pixel[3..].iter().map(|&alpha| alpha as u16 * 257));
+            }
+        }
+    }
+
+    fir::Image::from_vec_u8(crop.width, crop.height, depth::bytes(&values), pixel_type).unwrap()
+}
+
+/// sRGB pixels of the 16-bit linear light of `image`, rounded to 8 bits unless `deep`
+pub fn delinearize(image: &fir::Image, deep: bool) -> fir::Image<'static> {
+    let mut values = depth::values(image.buffer());
+    let channels = if depth::has_alpha(image) { 4 } else { 3 };
+    for pixel in values.chunks_exact_mut(channels) {
+        for value in &mut pixel[..3] {
+            *value = TO_SRGB[*value as usize];
+        }
+    }
+    let srgb =
+        fir::Image::from_vec_u8(image.width(), image.height(), depth::bytes(&values), image.pixel_type()).unwrap();
+
+    if deep {
+        srgb
+    } else {
+        depth::to_8_bit(&srgb).unwrap()
+    }
+}
@@ -1,249 +1,3332 @@
+mod access_log;
+mod animation;
+mod auto_quality;
+mod cache;
+mod check;
+mod color;
+mod config;
+mod cors;
+mod depth;
+mod effects;
+mod format;
+mod linear;
+mod metadata;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod placeholder;
+mod rate_limit;
+mod resize;
+mod signature;
+mod smart;
+mod source;
+mod sprite;
+mod srcset;
+mod svg;
+mod trim;
+#[cfg(unix)]
+mod unix;
+mod warmup;
+mod watermark;
+
 use std::{
-    net::SocketAddr,
-    num::NonZeroU32,
+    future::Future,
+    io::Cursor,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    num::{NonZeroU32, NonZeroUsize},
     path::PathBuf,
+    pin::Pin,
+    process::ExitCode,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
+use access_log::AccessLog;
+use animation::AnimationFrame;
 use axum::{
     body,
-    error_handling::HandleErrorLayer,
-    extract::{Extension, Path, Query},
-    http::{self, header, Method, StatusCode},
-    response::{AppendHeaders, IntoResponse},
-    routing::get,
-    BoxError, Router,
+    extract::{rejection::QueryRejection, ConnectInfo, ContentLengthLimit, Extension, Query},
+    http::{header, header::HeaderName, HeaderMap, HeaderValue, Request, StatusCode, Uri},
+    middleware::{self, Next},
+    response::{AppendHeaders, IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
 };
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use bytes::Bytes;
+use cache::{Cache, CacheStats, DiskCache, MemoryCache};
 use clap::Parser;
+use color::Color;
+use cors::CorsOrigin;
+use depth::BitDepth;
+use effects::{ColorEffect, Effects, Flip, Rotation, MAX_BLUR, MAX_SHARPEN};
 use fast_image_resize as fir;
-use image::{codecs::jpeg::JpegEncoder, ImageEncoder};
+use format::{
+    ChromaSubsampling, EncodeOptions, JpegEncoderKind, OutputFormat, PngCompression, PngFilter, Quality,
+    DEFAULT_AVIF_SPEED, DEFAULT_QUALITY,
+};
+use image::{codecs::jpeg::JpegDecoder, DynamicImage, ImageDecoder, ImageFormat};
+use metadata::Strip;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use object_store::aws::AmazonS3Builder;
+use placeholder::{ColorMode, Placeholder};
+use rate_limit::RateLimiter;
 use reqwest::Client;
-use serde::Deserialize;
+use resize::{CropRect, FitMode, FocalPoint, Gravity, Limits, ResizeFilter, ResizeOptions, ResizePlan};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use source::{
+    CachePolicy, FetchError, Fetched, HttpSource, LocalSource, Retry, S3Source, Source, SourceChain, SourceKind,
+};
+use tokio::{net::TcpSocket, sync::Semaphore};
 use tower_http::{
-    cors::{AllowOrigin, CorsLayer},
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
     trace::TraceLayer,
 };
+use tracing_subscriber::EnvFilter;
+use watermark::{Placement, Watermark};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, with the event fields as keys
+    Json,
+}
+
+/// Address given to `--bind`, `--port` giving the port when it has none
+#[derive(Debug, Clone, Copy)]
+struct Bind {
+    ip: IpAddr,
+    port: Option<u16>,
+}
 
-#[derive(Parser, Clone)]
+#[derive(Parser, Clone, Deserialize)]
 #[clap(version)]
+#[serde(default, deny_unknown_fields)]
 struct Cli {
+    /// TOML file of flag values keyed by their long name, flags given on the command line take precedence
+    #[clap(long, value_parser)]
+    #[serde(skip)]
+    config: Option<PathBuf>,
     #[clap(short, long, value_parser)]
     port: Option<u16>,
+    /// IP address (default 0.0.0.0) or `ip:port` to listen on, `[::1]:8080` for IPv6 with a port
+    #[clap(long, alias = "host", value_parser = parse_bind)]
+    #[serde(alias = "host")]
+    bind: Option<Bind>,
+    /// Unix domain socket to listen on instead of a TCP port, removed on shutdown
+    #[clap(long, value_parser)]
+    unix_socket: Option<PathBuf>,
+    /// PEM certificate chain to serve HTTPS and HTTP/2 with, along with `--tls-key`
+    #[clap(long, value_parser)]
+    tls_cert: Option<PathBuf>,
+    /// PEM private key of `--tls-cert`
+    #[clap(long, value_parser)]
+    tls_key: Option<PathBuf>,
     #[clap(short, long, value_parser)]
     remote_cdn: Option<String>,
-    #[clap(short, long, value_parser)]
-    local_folder: Option<String>,
+    /// Folder to read sources from, given several times (or comma separated) they are tried in order
+    #[clap(short, long, value_parser, value_delimiter = ',')]
+    local_folder: Option<Vec<String>>,
+    /// S3 bucket to fetch sources from, credentials are read from the standard `AWS_*` environment variables
+    #[clap(long, value_parser)]
+    s3_bucket: Option<String>,
+    /// Region of the S3 bucket
+    #[clap(long, value_parser)]
+    s3_region: Option<String>,
+    /// Endpoint of S3 compatible storages like MinIO
+    #[clap(long, value_parser)]
+    s3_endpoint: Option<String>,
+    /// Comma separated order the sources are tried in, defaults to `local,s3,remote` with the configured ones
+    #[clap(long, value_enum, value_delimiter = ',')]
+    sources: Option<Vec<SourceKind>>,
+    /// Background color (hex `rrggbb`) transparent images are flattened onto for formats without alpha
+    #[clap(long, value_parser = parse_hex_color, default_value = "ffffff")]
+    #[serde(deserialize_with = "deserialize_background")]
+    background: [u8; 3],
+    /// Directory to cache resized outputs in
+    #[clap(long, value_parser)]
+    cache_dir: Option<PathBuf>,
+    /// Size in megabytes of the in-memory cache of resized outputs
+    #[clap(long, value_parser)]
+    memory_cache_mb: Option<usize>,
+    /// Comma separated hosts sources may be fetched from over HTTP, others are answered with `403`
+    #[clap(long, value_parser, value_delimiter = ',')]
+    allowed_hosts: Option<Vec<String>>,
+    /// Size in megabytes of the in-memory cache of sources downloaded from `--remote-cdn`, revalidated with
+    /// `If-None-Match`/`If-Modified-Since` instead of downloaded again
+    #[clap(long, value_parser)]
+    origin_cache_mb: Option<usize>,
+    /// File listing `/path?query` requests, one per line, processed at startup to warm up the cache of outputs
+    #[clap(long, value_parser = warmup::load)]
+    warmup: Option<warmup::Manifest>,
+    /// Image stamped over every output, positioned by the `watermark_gravity` (default `southeast`) and faded by
+    /// the `watermark_opacity` (0-1) query parameters
+    #[clap(long, value_parser = watermark::load)]
+    watermark: Option<Watermark>,
+    /// Width of the watermark, in percent of the output width
+    #[clap(long, value_parser, default_value_t = 20.0)]
+    watermark_size: f32,
+    /// Output format when the request has no `format`, which also turns off the negotiation through `Accept`
+    #[clap(long, value_enum)]
+    default_format: Option<OutputFormat>,
+    /// Quality (1-100) of JPEG, WebP and AVIF outputs when the request has no `quality`
+    #[clap(long, value_parser, default_value_t = DEFAULT_QUALITY)]
+    default_quality: u8,
+    /// Quality of JPEG outputs when the request has no `quality`, instead of `--default-quality`
+    #[clap(long, value_parser)]
+    jpeg_quality: Option<u8>,
+    /// Quality of WebP outputs when the request has no `quality`, instead of `--default-quality`
+    #[clap(long, value_parser)]
+    webp_quality: Option<u8>,
+    /// Quality of AVIF outputs when the request has no `quality`, instead of `--default-quality`
+    #[clap(long, value_parser)]
+    avif_quality: Option<u8>,
+    /// DSSIM between the image and its output that `quality=auto` aims for, lower is closer to the image
+    #[clap(long, value_parser, default_value_t = 0.0015)]
+    auto_quality_target: f64,
+    /// Shrink outputs larger than the source (or its crop) to it unless the request has `upscale=true`, vector
+    /// sources excepted
+    #[clap(long, value_parser)]
+    no_upscale: bool,
+    /// Scale of the source when neither `width` nor `height` is requested, `0.25` for a quarter of it
+    #[clap(long, value_parser, default_value_t = 1.0)]
+    default_scale: f32,
+    /// Maximum output width
+    #[clap(long, value_parser, default_value_t = 4096)]
+    max_width: u32,
+    /// Maximum output height
+    #[clap(long, value_parser, default_value_t = 4096)]
+    max_height: u32,
+    /// Maximum output pixel count (width * height)
+    #[clap(long, value_parser, default_value_t = 4096 * 4096)]
+    max_pixels: u64,
+    /// Maximum pixel count of the raster sources decoded, read from their header, answered with `413` when exceeded
+    #[clap(long, value_parser, default_value_t = 128 * 1024 * 1024)]
+    max_source_pixels: u64,
+    /// Comma separated media types of non-image sources served as is to requests without parameters, such as
+    /// `text/plain` or `application/*`, as declared by the origin or guessed from the extension. Other non-images
+    /// are answered with `415`
+    #[clap(long, value_parser, value_delimiter = ',')]
+    passthrough_non_images: Option<Vec<String>>,
+    /// Maximum size in bytes of the sources fetched, answered with `413` when exceeded
+    #[clap(long, value_parser)]
+    max_source_bytes: Option<u64>,
+    /// Seconds images may be kept by shared caches, `Cache-Control` from `--remote-cdn` can only shorten it
+    #[clap(long, alias = "cache-success", value_parser, default_value_t = 2592000)]
+    #[serde(alias = "cache_success")]
+    max_cache_age: u64,
+    /// Seconds shared caches may keep the `404` of a missing source
+    #[clap(long, value_parser, default_value_t = 28800)]
+    cache_404: u64,
+    /// Seconds shared caches may keep the `500` of a source failing to decode, which may have been truncated
+    #[clap(long, value_parser, default_value_t = 300)]
+    cache_decode_error: u64,
+    /// Seconds shared caches may keep the `415` of a source in an unsupported format
+    #[clap(long, value_parser, default_value_t = 604800)]
+    cache_unsupported: u64,
+    /// Seconds shared caches may keep the `500` of an output failing to resize or encode
+    #[clap(long, value_parser, default_value_t = 28800)]
+    cache_process_error: u64,
+    /// Seconds shared caches may keep the `--fallback-image` served for a missing source
+    #[clap(long, value_parser, default_value_t = 300)]
+    cache_fallback: u64,
+    /// Local image served, resized as requested, when the source can't be fetched
+    #[clap(long, value_parser)]
+    fallback_image: Option<PathBuf>,
+    /// Secret requests must be signed with through the `sig` query parameter
+    #[clap(long, value_parser)]
+    signing_secret: Option<String>,
+    /// Token of the administration requests, sent as `Authorization: Bearer`: `DELETE` purging the cached outputs
+    /// of a source and `/cache/stats`. Without it neither is served
+    #[clap(long, value_parser, alias = "purge-token")]
+    #[serde(alias = "purge_token")]
+    admin_token: Option<String>,
+    /// Validate the configuration and that the sources, cache folder and files it points to are usable, print a
+    /// report and exit, with a non-zero status on any problem
+    #[clap(long, value_parser)]
+    check: bool,
+    /// Source fetched by `--check` through the configured sources and from the remote CDN, such as `/photo.jpg`.
+    /// Required by `--check` along with `--remote-cdn`
+    #[clap(long, value_parser)]
+    check_path: Option<String>,
+    /// Print the `sig` of a `/path?query` request signed with `--signing-secret` and exit
+    #[clap(long, value_parser)]
+    sign: Option<String>,
+    /// Seconds allowed to fetch a source from the remote CDN, answered with `504` when exceeded
+    #[clap(long, value_parser, default_value_t = 10)]
+    fetch_timeout: u64,
+    /// Retries of `--remote-cdn` fetches failing with a connection error or a `5xx`, after 100ms then doubling
+    /// waits, as long as they fit in `--fetch-timeout`
+    #[clap(long, value_parser, default_value_t = 0)]
+    fetch_retries: u32,
+    /// Seconds allowed to each HTTP request to the origins, connecting included, every retry getting its own
+    #[clap(long, value_parser)]
+    request_timeout: Option<u64>,
+    /// Idle connections kept open to each origin host for the next fetches
+    #[clap(long, value_parser, default_value_t = 32)]
+    pool_max_idle_per_host: usize,
+    /// Seconds an idle connection to an origin is kept open
+    #[clap(long, value_parser, default_value_t = 90)]
+    pool_idle_timeout: u64,
+    /// `User-Agent` of the requests to the origins, none is sent when absent
+    #[clap(long, value_parser)]
+    user_agent: Option<String>,
+    /// `Name: Value` header sent with every request to the origins, given several times for several headers
+    #[clap(long = "origin-header", value_parser = parse_origin_header)]
+    #[serde(rename = "origin_header", deserialize_with = "deserialize_origin_headers")]
+    origin_headers: Option<Vec<(HeaderName, HeaderValue)>>,
+    /// Comma separated headers of the client requests passed on to the origins, such as `Authorization`. Outputs are
+    /// still cached by URL only
+    #[clap(long, value_parser, value_delimiter = ',')]
+    #[serde(deserialize_with = "deserialize_header_names")]
+    forward_headers: Option<Vec<HeaderName>>,
+    /// Seconds allowed to decode, resize and encode an image, answered with `500` when exceeded
+    #[clap(long, value_parser, default_value_t = 20)]
+    process_timeout: u64,
+    /// Seconds allowed to decode a raster source, within `--process-timeout`. The decoding is then abandoned and the
+    /// request answered with `500`
+    #[clap(long, value_parser, default_value_t = 10)]
+    decode_timeout: u64,
+    /// Maximum number of threads decoding, resizing and encoding images at once, defaults to Tokio's 512
+    #[clap(long, value_parser)]
+    blocking_threads: Option<NonZeroUsize>,
+    /// Maximum number of images decoded, resized and encoded at once, the others wait up to `--process-timeout`
+    /// for their turn before being answered with `503`
+    #[clap(long, value_parser)]
+    max_concurrent: Option<NonZeroUsize>,
+    /// Maximum width and height SVG and PDF sources are rendered at, larger renders are rejected with `400`
+    #[clap(long, value_parser, default_value_t = 4096, alias = "max-svg-size")]
+    #[serde(alias = "max_svg_size")]
+    max_render_size: u32,
+    /// Maximum number of frames decoded from animated GIF and WebP sources
+    #[clap(long, value_parser, default_value_t = 256)]
+    max_frames: usize,
+    /// Requests per second allowed per client IP on image routes, answered with `429` when exceeded
+    #[clap(long, value_parser)]
+    rate_limit: Option<f64>,
+    /// Identify clients by the last `X-Forwarded-For` address, for deployments behind a reverse proxy
+    #[clap(long, value_parser)]
+    trust_forwarded_for: bool,
+    /// Comma separated origins allowed to request images from browsers: `*`, exact `https://example.com` origins
+    /// and `*.example.com` suffixes. Without it no cross-origin request is allowed
+    #[clap(long, value_parser, value_delimiter = ',')]
+    cors_origin: Option<Vec<CorsOrigin>>,
+    /// Leave out the CORS layer even with `--cors-origin`, for instance one from the `--config` file
+    #[clap(long, value_parser)]
+    no_cors: bool,
+    /// Seconds in-flight requests are given to finish after SIGTERM or SIGINT before exiting anyway
+    #[clap(long, value_parser, default_value_t = 30)]
+    shutdown_timeout: u64,
+    /// Encoder of JPEG outputs
+    #[clap(long, value_enum, default_value = "builtin")]
+    jpeg_encoder: JpegEncoderKind,
+    /// Send the fetch, decode, resize and encode durations in a `Server-Timing` header, for debugging from browsers
+    #[clap(long, value_parser)]
+    debug_timing: bool,
+    /// Encoding of the logs, filtered by `RUST_LOG` either way
+    #[clap(long, value_enum, default_value = "text")]
+    log_format: LogFormat,
+    /// Also log every request in the Common or Combined Log Format, for tools like GoAccess
+    #[clap(long, value_enum)]
+    access_log: Option<access_log::Format>,
+    /// File the `--access-log` lines are appended to, instead of stdout
+    #[clap(long, value_parser)]
+    access_log_file: Option<PathBuf>,
+}
+
+impl Default for Cli {
+    /// Default values of the flags, taken by the flags left out of the `--config` file
+    fn default() -> Self {
+        Cli::parse_from([env!("CARGO_PKG_NAME")])
+    }
+}
+
+fn main() -> ExitCode {
+    let cli = parse_cli();
+    init_logging(cli.as_ref().map_or(LogFormat::Text, |cli| cli.log_format));
+    let cli = match cli {
+        Ok(cli) => cli,
+        Err(err) => {
+            tracing::error!("{err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    runtime.enable_all();
+    if let Some(threads) = cli.blocking_threads {
+        runtime.max_blocking_threads(threads.get());
+    }
+    let runtime = runtime.build().unwrap();
+    let status = runtime.block_on(run(cli));
+    // Images still processing after the drain timeout would otherwise keep the process alive
+    runtime.shutdown_background();
+    status
+}
+
+fn init_logging(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Text => subscriber.init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
+}
+
+/// Parse the command line, completed by the `--config` file if any
+fn parse_cli() -> Result<Cli, String> {
+    config::parse(std::env::args_os().collect())
 }
 
-#[tokio::main]
-async fn main() {
-    tracing_subscriber::fmt::init();
-    let cli = Cli::parse();
+/// Serve until shut down, failing when the configuration is invalid
+async fn run(cli: Cli) -> ExitCode {
+    if let (Some(request), Some(secret)) = (&cli.sign, &cli.signing_secret) {
+        let (path, query) = request.split_once('?').unwrap_or((request, ""));
+        println!("{}", signature::sign(secret.as_bytes(), path, query));
+        return ExitCode::SUCCESS;
+    }
 
-    if cli.remote_cdn.is_none() && cli.local_folder.is_none() {
-        tracing::error!("Either 'remote_cdn' or 'local_folder' is required");
-        return;
+    // `--check` reports every problem, the server refuses to start on the first one
+    let mut problems = flag_problems(&cli);
+    if let Some(problem) = problems.first().filter(|_| !cli.check) {
+        tracing::error!("{problem}");
+        return ExitCode::FAILURE;
     }
 
-    let client = Client::builder()
+    let mut client = Client::builder()
         .gzip(true)
         .brotli(true)
         .connect_timeout(Duration::new(1, 0))
-        .build()
+        .pool_max_idle_per_host(cli.pool_max_idle_per_host)
+        .pool_idle_timeout(Duration::from_secs(cli.pool_idle_timeout));
+    if let Some(timeout) = cli.request_timeout {
+        client = client.timeout(Duration::from_secs(timeout));
+    }
+    if let Some(user_agent) = &cli.user_agent {
+        client = client.user_agent(user_agent);
+    }
+    if let Some(headers) = &cli.origin_headers {
+        client = client.default_headers(headers.iter().cloned().collect());
+    }
+    let client = client.build().unwrap();
+
+    // Unless an order is given, every configured source is used from the closest to the furthest
+    let order = cli
+        .sources
+        .clone()
+        .unwrap_or_else(|| vec![SourceKind::Local, SourceKind::S3, SourceKind::Remote]);
+    let mut sources = Vec::new();
+    for kind in order {
+        match build_source(kind, &cli, &client) {
+            Ok(Some(source)) => sources.push(source),
+            Ok(None) if cli.sources.is_some() => {
+                problems.push(format!("The {kind:?} source is listed in 'sources' but not configured"));
+            }
+            Ok(None) => {}
+            Err(err) => problems.push(format!("Failed to configure the {kind:?} source: {err}")),
+        }
+    }
+    let sources = Arc::new(SourceChain::new(sources));
+
+    // Before anything is written, the cache folder or the access log file
+    if cli.check {
+        let report = check::run(&cli, &problems, &client, &sources).await;
+        return if report.passed() {
+            println!("Configuration is valid");
+            ExitCode::SUCCESS
+        } else {
+            ExitCode::FAILURE
+        };
+    }
+    if let Some(problem) = problems.first() {
+        tracing::error!("{problem}");
+        return ExitCode::FAILURE;
+    }
+
+    let cors_origin = cli.cors_origin.as_deref().filter(|_| !cli.no_cors);
+    let cors = cors_origin.map(|origins| cors::layer(origins).expect("checked with the other flags"));
+
+    let disk_cache = match cli.cache_dir.as_ref().map(DiskCache::new).transpose() {
+        Ok(cache) => cache,
+        Err(err) => {
+            tracing::error!("Failed to create cache directory: {err:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let memory_cache = cli.memory_cache_mb.map(|mb| MemoryCache::new(mb * 1024 * 1024));
+    let cache = Arc::new(Cache::new(memory_cache, disk_cache, cache::config_fingerprint(&cli)));
+    let cache_enabled = cache.is_enabled();
+    let permits = cli
+        .max_concurrent
+        .map(|permits| Arc::new(Semaphore::new(permits.get())));
+
+    let prometheus = PrometheusBuilder::new()
+        .set_buckets(&[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0])
+        .unwrap()
+        .install_recorder()
         .unwrap();
+    tokio::spawn({
+        let prometheus = prometheus.clone();
+        async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                prometheus.run_upkeep();
+            }
+        }
+    });
+
+    // Images are served from the fallback since `/*path` would conflict with any other route
+    let image_routes = match cli.admin_token {
+        Some(_) => get(handler).delete(purge_handler),
+        None => get(handler),
+    };
+    let mut images = Router::new()
+        .route("/batch", post(batch_handler))
+        .route("/sprite", post(sprite_handler))
+        .fallback(image_routes);
+    if let Some(rate) = cli.rate_limit {
+        let limiter = Arc::new(RateLimiter::new(rate));
+        tokio::spawn({
+            let limiter = limiter.clone();
+            async move {
+                let mut interval = tokio::time::interval(Duration::from_secs(60));
+                loop {
+                    interval.tick().await;
+                    limiter.prune();
+                }
+            }
+        });
+
+        let trust_forwarded_for = cli.trust_forwarded_for;
+        images = images.layer(middleware::from_fn(move |req, next| {
+            rate_limit(limiter.clone(), trust_forwarded_for, req, next)
+        }));
+    }
+    if let Some(cors) = cors {
+        images = images.layer(cors);
+    }
 
-    let app = Router::new()
-        .route("/*path", get(handler))
-        .layer(Extension(client))
+    // Routes outside of the CORS layer
+    let mut app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .route("/healthz", get(health_handler));
+    if cli.admin_token.is_some() {
+        app = app.route("/cache/stats", get(cache_stats_handler));
+    }
+    let mut app = app
+        .merge(images)
+        .layer(Extension(client.clone()))
+        .layer(Extension(sources.clone()))
+        .layer(Extension(cache))
+        .layer(Extension(permits))
         .layer(Extension(cli.clone()))
-        .layer(
-            CorsLayer::new()
-                .allow_origin(AllowOrigin::predicate(
-                    |origin: &hyper::http::HeaderValue, _request_parts: &http::request::Parts| {
-                        origin.as_bytes().ends_with(b".remtori.com")
-                    },
-                ))
-                .allow_methods([Method::GET]),
-        )
-        .layer(
-            tower::ServiceBuilder::new()
-                .layer(HandleErrorLayer::new(handle_error))
-                .timeout(Duration::from_secs(30)),
-        )
-        .layer(TraceLayer::new_for_http());
+        .layer(Extension(prometheus))
+        // Every log of a request carries its `X-Request-Id`, the client's one or a generated UUID
+        .layer(TraceLayer::new_for_http().make_span_with(|req: &Request<body::Body>| {
+            let request_id = req
+                .headers()
+                .get("x-request-id")
+                .and_then(|id| id.to_str().ok())
+                .unwrap_or_default();
+            tracing::info_span!("request", method = %req.method(), uri = %req.uri(), request_id)
+        }))
+        .layer(PropagateRequestIdLayer::x_request_id())
+        .layer(SetRequestIdLayer::x_request_id(MakeRequestUuid));
+    if let Some(format) = cli.access_log {
+        let access_log = match AccessLog::new(format, cli.access_log_file.as_deref(), cli.trust_forwarded_for) {
+            Ok(access_log) => Arc::new(access_log),
+            Err(err) => {
+                tracing::error!("Failed to open the access log: {err}");
+                return ExitCode::FAILURE;
+            }
+        };
+        app = app.layer(middleware::from_fn(move |req, next| {
+            access_log::log(access_log.clone(), req, next)
+        }));
+    }
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], cli.port.unwrap_or(3000)));
+    let bind = cli.bind.unwrap_or(Bind {
+        ip: IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        port: None,
+    });
+    let addr = SocketAddr::new(bind.ip, bind.port.or(cli.port).unwrap_or(3000));
 
     tracing::info!("Running image resize server with:");
-    if let Some(folder) = cli.local_folder {
-        tracing::info!("\tlocal folder: {folder}");
+    if let Some(folders) = cli.local_folder {
+        tracing::info!("\tlocal folders: {}", folders.join(","));
+    }
+    if let Some(bucket) = cli.s3_bucket {
+        tracing::info!("\ts3 bucket: {bucket}");
     }
     if let Some(url) = cli.remote_cdn {
         tracing::info!("\tremote cdn: {url}");
     }
+    if let Some(dir) = cli.cache_dir {
+        tracing::info!("\tcache dir: {}", dir.display());
+    }
+    if let Some(mb) = cli.memory_cache_mb {
+        tracing::info!("\tmemory cache: {mb}MB");
+    }
+    if let Some(path) = cli.fallback_image {
+        tracing::info!("\tfallback image: {}", path.display());
+    }
+    if cli.signing_secret.is_some() {
+        tracing::info!("\tsigned urls required");
+    }
+    if let Some(permits) = cli.max_concurrent {
+        tracing::info!("\tmax concurrent: {permits}");
+    }
+    if let Some(origins) = cors_origin {
+        let origins = origins.iter().map(ToString::to_string).collect::<Vec<_>>();
+        tracing::info!("\tcors origins: {}", origins.join(","));
+    }
+    if let Some(rate) = cli.rate_limit {
+        tracing::info!("\trate limit: {rate} requests per second per client");
+    }
 
-    tracing::info!("Listening on {}", addr);
-    axum::Server::bind(&addr).serve(app.into_make_service()).await.unwrap();
+    let tls = match (&cli.tls_cert, &cli.tls_key) {
+        (Some(cert), Some(key)) => match RustlsConfig::from_pem_file(cert, key).await {
+            Ok(tls) => {
+                tracing::info!("\ttls certificate: {}", cert.display());
+                Some(tls)
+            }
+            Err(err) => {
+                tracing::error!("Failed to load the TLS certificate and key: {err}");
+                return ExitCode::FAILURE;
+            }
+        },
+        _ => None,
+    };
+
+    let (draining, drain_started) = tokio::sync::oneshot::channel();
+    let drain = async {
+        shutdown_signal().await;
+        tracing::info!("Shutting down, waiting for in-flight requests");
+        let _ = draining.send(());
+    };
+    let warm_up = match cli.warmup.clone() {
+        Some(manifest) if cache_enabled => Some((app.clone(), manifest)),
+        Some(_) => {
+            tracing::info!("Neither 'cache_dir' nor 'memory_cache_mb' is set, the warm-up is a no-op");
+            None
+        }
+        None => None,
+    };
+    let server: Pin<Box<dyn Future<Output = ()> + Send>> = match (tls, &cli.unix_socket) {
+        (Some(tls), _) => {
+            let listener = match listen(addr) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    tracing::error!("Failed to listen on {addr}: {err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            tracing::info!("Listening on {}", addr);
+            let make_service = app.into_make_service_with_connect_info::<SocketAddr>();
+            let handle = axum_server::Handle::new();
+            tokio::spawn({
+                let handle = handle.clone();
+                async move {
+                    drain.await;
+                    handle.graceful_shutdown(None);
+                }
+            });
+            let server = axum_server::from_tcp_rustls(listener, tls)
+                .handle(handle)
+                .serve(make_service);
+            Box::pin(async { server.await.unwrap() })
+        }
+        #[cfg(unix)]
+        (None, Some(path)) => {
+            let accept = match unix::UnixAccept::bind(path) {
+                Ok(accept) => accept,
+                Err(err) => {
+                    tracing::error!("Failed to listen on {}: {err}", path.display());
+                    return ExitCode::FAILURE;
+                }
+            };
+            tracing::info!("Listening on {}", path.display());
+            let server = axum::Server::builder(accept)
+                .serve(app.into_make_service())
+                .with_graceful_shutdown(drain);
+            Box::pin(async { server.await.unwrap() })
+        }
+        #[cfg(not(unix))]
+        (None, Some(_)) => {
+            tracing::error!("'unix_socket' is only supported on Unix");
+            return ExitCode::FAILURE;
+        }
+        (None, None) => {
+            let listener = match listen(addr) {
+                Ok(listener) => listener,
+                Err(err) => {
+                    tracing::error!("Failed to listen on {addr}: {err}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            tracing::info!("Listening on {}", addr);
+            let server = axum::Server::from_tcp(listener)
+                .expect("the listener is non-blocking")
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .with_graceful_shutdown(drain);
+            Box::pin(async { server.await.unwrap() })
+        }
+    };
+
+    if let Some((app, manifest)) = warm_up {
+        tokio::spawn(warmup::run(app, manifest));
+    }
+
+    tokio::pin!(server);
+    let drained = tokio::select! {
+        () = &mut server => true,
+        Ok(()) = drain_started => false,
+    };
+    if !drained {
+        match tokio::time::timeout(Duration::from_secs(cli.shutdown_timeout), server).await {
+            Ok(()) => {}
+            Err(_) => tracing::warn!(
+                "In-flight requests didn't finish within {}s, exiting",
+                cli.shutdown_timeout
+            ),
+        }
+    }
+
+    if let Some(path) = &cli.unix_socket {
+        if let Err(err) = std::fs::remove_file(path) {
+            tracing::warn!("Failed to remove {}: {err}", path.display());
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+/// Bind the TCP listener before serving, so an address that can't be listened on is reported instead of panicking.
+/// Bound as tokio's `TcpListener::bind` does, which hyper and axum-server bind with.
+fn listen(addr: SocketAddr) -> std::io::Result<std::net::TcpListener> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+
+    socket.listen(1024)?.into_std()
 }
 
-async fn handle_error(err: BoxError) -> StatusCode {
-    tracing::warn!("Unhandled error: {err:#}");
-    StatusCode::NOT_FOUND
+/// Wait for SIGINT (Ctrl+C), or SIGTERM on Unix
+async fn shutdown_signal() {
+    let interrupt = async {
+        tokio::signal::ctrl_c().await.expect("failed to listen for SIGINT");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => {}
+        _ = terminate => {}
+    }
 }
 
-async fn handler(
-    Extension(client): Extension<Client>,
-    Extension(config): Extension<Cli>,
-    Query(params): Query<Params>,
-    Path(path): Path<String>,
-) -> impl IntoResponse {
-    let start = Instant::now();
+/// Flags that are incoherent, alone or together
+fn flag_problems(cli: &Cli) -> Vec<String> {
+    let mut problems = Vec::new();
+    // Checked once the command line and the config file are merged, either may give the required flag
+    let requirements = [
+        ("tls_cert", cli.tls_cert.is_some(), "tls_key", cli.tls_key.is_some()),
+        ("tls_key", cli.tls_key.is_some(), "tls_cert", cli.tls_cert.is_some()),
+        (
+            "s3_region",
+            cli.s3_region.is_some(),
+            "s3_bucket",
+            cli.s3_bucket.is_some(),
+        ),
+        (
+            "s3_endpoint",
+            cli.s3_endpoint.is_some(),
+            "s3_bucket",
+            cli.s3_bucket.is_some(),
+        ),
+        ("check_path", cli.check_path.is_some(), "check", cli.check),
+        (
+            "sign",
+            cli.sign.is_some(),
+            "signing_secret",
+            cli.signing_secret.is_some(),
+        ),
+        (
+            "access_log_file",
+            cli.access_log_file.is_some(),
+            "access_log",
+            cli.access_log.is_some(),
+        ),
+    ];
+    for (name, given, required, present) in requirements {
+        if given && !present {
+            problems.push(format!("'{name}' requires '{required}'"));
+        }
+    }
 
-    let mut bytes = None;
-    if let Some(file_path) = config.local_folder {
-        let mut file_path = PathBuf::from(file_path);
-        file_path.push(&path);
+    if cli.check && cli.remote_cdn.is_some() && cli.check_path.is_none() {
+        problems.push("'check' requires 'check_path' to fetch a sample from 'remote_cdn'".to_owned());
+    }
 
-        match tokio::fs::read(file_path).await {
-            Ok(data) => bytes = Some(bytes::Bytes::from(data)),
-            Err(err) => {
-                if err.kind() != std::io::ErrorKind::NotFound {
-                    tracing::error!(path, "Read local file error {err:#}");
+    if cli.unix_socket.is_some() && (cli.port.is_some() || cli.bind.is_some() || cli.tls_cert.is_some()) {
+        problems.push("'unix_socket' can't be combined with 'port', 'bind' or 'tls_cert'".to_owned());
+    }
+
+    if cli.remote_cdn.is_none() && cli.local_folder.is_none() && cli.s3_bucket.is_none() {
+        problems.push("Either 'remote_cdn', 'local_folder' or 's3_bucket' is required".to_owned());
+    }
+
+    if !(cli.default_scale > 0.0 && cli.default_scale <= 1.0) {
+        problems.push("'default_scale' must be greater than 0 and at most 1".to_owned());
+    }
+
+    if !(cli.watermark_size > 0.0 && cli.watermark_size <= 100.0) {
+        problems.push("'watermark_size' must be greater than 0 and at most 100".to_owned());
+    }
+
+    let qualities = [
+        ("default_quality", Some(cli.default_quality)),
+        ("jpeg_quality", cli.jpeg_quality),
+        ("webp_quality", cli.webp_quality),
+        ("avif_quality", cli.avif_quality),
+    ];
+    for (name, quality) in qualities {
+        if quality.is_some_and(|quality| !(1..=100).contains(&quality)) {
+            problems.push(format!("'{name}' must be between 1 and 100"));
+        }
+    }
+
+    if let Some(format) = cli.default_format.filter(|format| !format.is_available()) {
+        problems.push(format!(
+            "'default_format' {} isn't available in this build",
+            format.extension()
+        ));
+    }
+
+    if !(cli.auto_quality_target > 0.0 && cli.auto_quality_target.is_finite()) {
+        problems.push("'auto_quality_target' must be a positive number".to_owned());
+    }
+
+    if cli.rate_limit.is_some_and(|rate| !(rate > 0.0 && rate.is_finite())) {
+        problems.push("'rate_limit' must be a positive number of requests per second".to_owned());
+    }
+
+    if cli.bind.is_some_and(|bind| bind.port.is_some()) && cli.port.is_some() {
+        problems.push("'bind' already has a port, 'port' can't be given too".to_owned());
+    }
+
+    if let Some(Err(err)) = cli.cors_origin.as_deref().map(cors::layer) {
+        problems.push(format!("Invalid 'cors_origin': {err}"));
+    }
+
+    problems
+}
+
+/// Build the source of the given kind from its flags, `None` when it isn't configured
+fn build_source(kind: SourceKind, config: &Cli, client: &Client) -> Result<Option<Box<dyn Source>>, String> {
+    let source: Box<dyn Source> = match kind {
+        SourceKind::Local => match &config.local_folder {
+            // The first folder having the file wins
+            Some(folders) => Box::new(SourceChain::new(
+                folders
+                    .iter()
+                    .map(|folder| Box::new(LocalSource::new(folder, config.max_source_bytes)) as Box<dyn Source>)
+                    .collect(),
+            )),
+            None => return Ok(None),
+        },
+        SourceKind::S3 => match &config.s3_bucket {
+            Some(bucket) => {
+                let mut builder = AmazonS3Builder::from_env().with_bucket_name(bucket);
+                if let Some(region) = &config.s3_region {
+                    builder = builder.with_region(region);
+                }
+                if let Some(endpoint) = &config.s3_endpoint {
+                    builder = builder
+                        .with_allow_http(endpoint.starts_with("http://"))
+                        .with_endpoint(endpoint);
+                }
+
+                Box::new(S3Source::new(
+                    builder.build().map_err(|err| format!("{err:#}"))?,
+                    config.max_source_bytes,
+                ))
+            }
+            None => return Ok(None),
+        },
+        SourceKind::Remote => match &config.remote_cdn {
+            Some(url) => {
+                let originals = config.origin_cache_mb.map(|mb| MemoryCache::new(mb * 1024 * 1024));
+                let retry = Retry {
+                    retries: config.fetch_retries,
+                    budget: Duration::from_secs(config.fetch_timeout),
+                };
+                let source = HttpSource::new(
+                    client.clone(),
+                    url.clone(),
+                    config.allowed_hosts.clone(),
+                    originals,
+                    retry,
+                    config.max_source_bytes,
+                );
+                if !source.is_allowed(url) {
+                    return Err(format!("The host of '{url}' is not in 'allowed_hosts'"));
                 }
+                Box::new(source)
             }
+            None => return Ok(None),
+        },
+    };
+
+    Ok(Some(source))
+}
+
+/// Answer `429 Too Many Requests` to clients sending image requests faster than allowed
+async fn rate_limit<B>(
+    limiter: Arc<RateLimiter>,
+    trust_forwarded_for: bool,
+    req: Request<B>,
+    next: Next<B>,
+) -> Response {
+    // Not a client, and without an address it would spend the bucket of the localhost ones
+    if req.extensions().get::<warmup::WarmUp>().is_some() {
+        return next.run(req).await;
+    }
+    // Clients of a Unix socket have no address, they are the local reverse proxy
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map_or(IpAddr::from(Ipv4Addr::LOCALHOST), |ConnectInfo(addr)| addr.ip());
+    let ip = rate_limit::client_ip(req.headers(), peer, trust_forwarded_for);
+
+    match limiter.check(ip) {
+        Ok(()) => next.run(req).await,
+        Err(wait) => {
+            metrics::counter!("image_resize_rate_limited_total").increment(1);
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            (
+                StatusCode::TOO_MANY_REQUESTS,
+                AppendHeaders([(header::RETRY_AFTER, retry_after.to_string())]),
+                "Too many requests",
+            )
+                .into_response()
         }
     }
+}
 
-    if bytes.is_none() {
-        if let Some(mut url) = config.remote_cdn {
-            if url.chars().last().unwrap() == '/' {
-                url.push_str(&path[1..]);
-            } else {
-                url.push_str(&path);
+async fn metrics_handler(Extension(prometheus): Extension<PrometheusHandle>) -> String {
+    prometheus.render()
+}
+
+#[derive(Debug, Deserialize)]
+struct HealthParams {
+    /// Also check that the configured sources are reachable
+    #[serde(default)]
+    ready: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct Health {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    local_folder: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    remote_cdn: Option<bool>,
+}
+
+async fn health_handler(
+    Extension(client): Extension<Client>,
+    Extension(config): Extension<Cli>,
+    Query(params): Query<HealthParams>,
+) -> impl IntoResponse {
+    let mut health = Health {
+        status: "ok",
+        local_folder: None,
+        remote_cdn: None,
+    };
+
+    if params.ready {
+        if let Some(folders) = &config.local_folder {
+            let mut readable = true;
+            for folder in folders {
+                readable &= tokio::fs::read_dir(folder).await.is_ok();
             }
+            health.local_folder = Some(readable);
+        }
+        if let Some(url) = &config.remote_cdn {
+            // CDN roots commonly answer 403 or 404, only an unanswered request or a server error counts as down
+            let resp = client.head(url).timeout(Duration::from_secs(2)).send().await;
+            health.remote_cdn = Some(resp.is_ok_and(|resp| !resp.status().is_server_error()));
+        }
+    }
 
-            match client.get(url).send().await {
-                Ok(resp) if resp.status().is_success() => match resp.bytes().await {
-                    Ok(data) => bytes = Some(data),
-                    Err(err) => {
-                        tracing::error!(path, "Request get bytes error {err:#}");
-                    }
-                },
-                Ok(resp) => {
-                    tracing::info!(path, "Request error: status code {}", resp.status());
-                }
-                Err(err) => {
-                    tracing::info!(path, "Request error {err:#}");
-                }
+    let status = if health.local_folder == Some(false) || health.remote_cdn == Some(false) {
+        health.status = "unavailable";
+        StatusCode::SERVICE_UNAVAILABLE
+    } else {
+        StatusCode::OK
+    };
+
+    (status, Json(health))
+}
+
+async fn handler(
+    Extension(config): Extension<Cli>,
+    Extension(sources): Extension<Arc<SourceChain>>,
+    Extension(cache): Extension<Arc<Cache>>,
+    Extension(permits): Extension<Option<Arc<Semaphore>>>,
+    params: Result<Query<Params>, QueryRejection>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let start = Instant::now();
+    metrics::counter!("image_resize_requests_total").increment(1);
+    let Query(mut params) = params.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()).into_response())?;
+    // Without a `dpr` the density the browser sends as a client hint applies, the response then varies on it
+    let hinted_dpr = params.dpr.is_none();
+    if hinted_dpr {
+        params.dpr = client_hint_dpr(&headers);
+    }
+    let path = percent_encoding::percent_decode_str(uri.path())
+        .decode_utf8()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Path is not valid UTF-8").into_response())?
+        .into_owned();
+
+    if let Some(secret) = &config.signing_secret {
+        if !signature::verify(secret.as_bytes(), uri.path(), uri.query().unwrap_or_default()) {
+            return Err((StatusCode::FORBIDDEN, "Invalid signature").into_response());
+        }
+    }
+
+    // An explicit or default format always wins, otherwise pick the best one the client accepts
+    let negotiated = params.format().is_none() && config.default_format.is_none();
+    let format = params
+        .format()
+        .or(config.default_format)
+        .or_else(|| format::negotiate_format(&headers));
+
+    let ages = CacheAges::new(&config);
+    check_params(&params, &config).map_err(|err| err.response(ages))?;
+    let blurhash_components = (params.components_x.unwrap_or(4), params.components_y.unwrap_or(3));
+    if !(1..=9).contains(&blurhash_components.0) || !(1..=9).contains(&blurhash_components.1) {
+        return Err((StatusCode::BAD_REQUEST, "BlurHash components must be between 1 and 9").into_response());
+    }
+
+    // Without any parameter but the signature the source is served as is, skipping the cache of outputs
+    let passthrough = uri
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .all(|pair| pair.is_empty() || pair.split('=').next() == Some("sig"));
+
+    let cache_key = cache.key(&path, &params, format);
+    let cached = if passthrough { None } else { cache.get(&cache_key).await };
+    if let Some(data) = cached {
+        if let Some(format) = OutputFormat::guess(&data) {
+            tracing::info!(path, "Cache hit");
+            metrics::counter!("image_resize_cache_hits_total").increment(1);
+            let cache_control = ages.header(Cached::Success(None));
+            let mut response_headers = image_headers(format, negotiated, hinted_dpr, cache_control);
+            if params.download == Some(true) {
+                response_headers.insert(header::CONTENT_DISPOSITION, content_disposition(&path, format));
             }
+            return Ok(image_response(response_headers, data, &headers));
         }
     }
+    if cache.is_enabled() && !passthrough {
+        metrics::counter!("image_resize_cache_misses_total").increment(1);
+    }
 
+    let (
+        Fetched {
+            data: bytes,
+            cache_policy,
+            content_type,
+        },
+        fallback,
+    ) = fetch_source(&path, &headers, &sources, &config).await?;
     let time_fetch = start.elapsed();
-    let start = Instant::now();
-    let bytes = bytes.ok_or_else(|| {
-        (
-            StatusCode::NOT_FOUND,
-            AppendHeaders([(header::CACHE_CONTROL, "public, s-max-age=28800")]),
-        )
-            .into_response()
-    })?;
 
-    let image = image::load_from_memory(&bytes[..]).map_err(|err| {
-        // Cache this response since this file most likely is not an image
-        tracing::error!(path, "Decode image error {err:#}");
-        (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            AppendHeaders([(header::CACHE_CONTROL, "public, s-max-age=604800")]),
-            "Decode image error",
-        )
-            .into_response()
-    })?;
+    let cache_control = ages.header(if fallback {
+        Cached::Fallback
+    } else {
+        Cached::Success(cache_policy)
+    });
+    // Outputs kept by the server cache are served for `--max-cache-age`, whatever the origin said
+    let cacheable = !fallback
+        && cache_policy.is_none_or(|policy| matches!(policy, CachePolicy::MaxAge(age) if age >= config.max_cache_age));
 
-    let time_decode = start.elapsed();
-    let start = Instant::now();
-    let src_image = fir::Image::from_vec_u8(
-        NonZeroU32::new(image.width()).unwrap(),
-        NonZeroU32::new(image.height()).unwrap(),
-        image.to_rgb8().into_raw(),
-        fir::PixelType::U8x3,
-    )
-    .unwrap();
-
-    let (dst_width, dst_height) = {
-        let width = params.width.or(params.w);
-        let height = params.height.or(params.h);
-
-        if let (Some(width), Some(height)) = (width, height) {
-            (width.get(), height.get())
-        } else if let Some(width) = width {
-            let ratio = width.get() as f32 / src_image.width().get() as f32;
-            (width.get(), (src_image.height().get() as f32 * ratio) as u32)
-        } else if let Some(height) = height {
-            let ratio = height.get() as f32 / src_image.height().get() as f32;
-            ((src_image.width().get() as f32 * ratio) as u32, height.get())
-        } else {
-            (src_image.width().get() / 4, src_image.height().get() / 4)
+    let passthrough_type = passthrough
+        .then(|| match format::source_content_type(&bytes) {
+            Some(content_type) => Some(HeaderValue::from_static(content_type)),
+            None => config
+                .passthrough_non_images
+                .as_deref()
+                .and_then(|allowed| non_image_content_type(&path, content_type.as_ref(), allowed)),
+        })
+        .flatten();
+    if let Some(content_type) = passthrough_type {
+        tracing::info!(path, ?content_type, "Source passed through");
+        metrics::counter!("image_resize_passthrough_total").increment(1);
+        let mut response_headers = HeaderMap::new();
+        response_headers.insert(header::CONTENT_TYPE, content_type);
+        response_headers.insert(header::X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff"));
+        response_headers.insert(header::CACHE_CONTROL, cache_control);
+        if config.debug_timing {
+            response_headers.insert("server-timing", server_timing(&[("fetch", time_fetch)]));
         }
-    };
+        return Ok(image_response(response_headers, bytes, &headers));
+    }
 
-    let mut dst_image = fir::Image::new(
-        NonZeroU32::new(dst_width).unwrap(),
-        NonZeroU32::new(dst_height).unwrap(),
-        src_image.pixel_type(),
-    );
+    let process_timeout = Duration::from_secs(config.process_timeout);
+    if params.blurhash == Some(true) || params.color.is_some() {
+        let (components_x, components_y) = blurhash_components;
+        let color = params.color;
+        let decode_limits = decode_limits(&config);
+        let placeholder = run_blocking(&path, process_timeout, ages, permits.as_ref(), {
+            let path = path.clone();
+            move || {
+                let image = decode(&path, &bytes, &decode_limits)?;
+                match color {
+                    Some(mode) => Ok(Placeholder::Color(placeholder::color(&image, mode))),
+                    None => placeholder::blurhash(&image, components_x, components_y)
+                        .map(Placeholder::BlurHash)
+                        .map_err(|err| ProcessError::Invalid(err.to_string())),
+                }
+            }
+        })
+        .await?;
 
-    let mut resizer = fir::Resizer::new(fir::ResizeAlg::Convolution(fir::FilterType::Lanczos3));
-    if let Err(err) = resizer.resize(&src_image.view(), &mut dst_image.view_mut()) {
-        tracing::error!(path, "Resize image error {err:#}");
-        return Err((
-            StatusCode::INTERNAL_SERVER_ERROR,
-            AppendHeaders([(header::CACHE_CONTROL, "public, s-max-age=28800")]),
-            "Resize image error",
+        return Ok((
+            AppendHeaders([(header::CACHE_CONTROL, cache_control)]),
+            Json(placeholder),
         )
             .into_response());
     }
 
-    let time_resize = start.elapsed();
-    let start = Instant::now();
+    if params.info == Some(true) {
+        let info = run_blocking(&path, process_timeout, ages, permits.as_ref(), {
+            let path = path.clone();
+            move || source_info(&path, &bytes, &params)
+        })
+        .await?;
 
-    let mut result_buf = Vec::new();
-    JpegEncoder::new(&mut result_buf)
-        .write_image(
-            dst_image.buffer(),
-            dst_image.width().get(),
-            dst_image.height().get(),
-            image::ColorType::Rgb8,
+        return Ok((AppendHeaders([(header::CACHE_CONTROL, cache_control)]), Json(info)).into_response());
+    }
+
+    if let Some(widths) = params.srcset.clone() {
+        let size = run_blocking(&path, process_timeout, ages, permits.as_ref(), {
+            let path = path.clone();
+            move || source_size(&path, &bytes, &params).map(|size| (size, params))
+        })
+        .await;
+        let (size, params) = size?;
+        let manifest = srcset::manifest(
+            uri.path(),
+            uri.query().unwrap_or_default(),
+            config.signing_secret.as_deref().map(str::as_bytes),
+            size,
+            &widths,
+            &resize_options(&params, &config),
+            &limits(&config),
         )
-        .unwrap();
+        .map_err(|err| (StatusCode::BAD_REQUEST, err).into_response())?;
+
+        return Ok((AppendHeaders([(header::CACHE_CONTROL, cache_control)]), Json(manifest)).into_response());
+    }
+
+    let progressive = params.progressive == Some(true);
+    let download = params.download == Some(true);
+    let debug_timing = config.debug_timing;
+    let processed = run_blocking(&path, process_timeout, ages, permits.as_ref(), {
+        let (path, cache_key) = (path.clone(), cache_key.clone());
+        move || process(&path, &bytes, &params, format, &cache_key, &config)
+    })
+    .await?;
 
-    let time_encode = start.elapsed();
     tracing::info!(
-        "Image processed path={} original={}x{} resized={}x{} fetch={}ms decode={}ms resize={}ms encode={}ms",
         path,
-        src_image.width(),
-        src_image.height(),
-        dst_image.width(),
-        dst_image.height(),
-        time_fetch.as_millis(),
-        time_decode.as_millis(),
-        time_resize.as_millis(),
-        time_encode.as_millis(),
+        format = ?processed.format,
+        quality = processed.quality,
+        progressive = processed.format == OutputFormat::Jpeg && progressive,
+        filter = ?processed.filter,
+        original = %format_args!("{}x{}", processed.original.0, processed.original.1),
+        resized = %format_args!("{}x{}", processed.resized.0, processed.resized.1),
+        fetch_ms = time_fetch.as_millis() as u64,
+        decode_ms = processed.time_decode.as_millis() as u64,
+        resize_ms = processed.time_resize.as_millis() as u64,
+        encode_ms = processed.time_encode.as_millis() as u64,
+        "Image processed"
     );
+    metrics::histogram!("image_resize_fetch_seconds").record(time_fetch);
+    metrics::histogram!("image_resize_decode_seconds").record(processed.time_decode);
+    metrics::histogram!("image_resize_resize_seconds").record(processed.time_resize);
+    metrics::histogram!("image_resize_encode_seconds").record(processed.time_encode);
 
-    Ok((
-        AppendHeaders([
-            (header::CONTENT_TYPE, "image/jpeg"),
-            (header::CACHE_CONTROL, "public, s-max-age=2592000"),
-        ]),
-        body::Full::new(bytes::Bytes::from(result_buf)),
-    ))
+    let result_buf = bytes::Bytes::from(processed.data);
+    let mut response_headers = image_headers(processed.format, negotiated, hinted_dpr, cache_control);
+    if debug_timing {
+        response_headers.insert(
+            "server-timing",
+            server_timing(&[
+                ("fetch", time_fetch),
+                ("decode", processed.time_decode),
+                ("resize", processed.time_resize),
+                ("encode", processed.time_encode),
+            ]),
+        );
+    }
+    if download {
+        response_headers.insert(
+            header::CONTENT_DISPOSITION,
+            content_disposition(&path, processed.format),
+        );
+    }
+    if cacheable && cache.is_enabled() {
+        let data = result_buf.clone();
+        tokio::spawn(async move { cache.put(&cache_key, data).await });
+    }
+
+    Ok(image_response(response_headers, result_buf, &headers))
+}
+
+/// Rejection of the administration requests without the `--admin-token` bearer token
+fn admin_rejection(headers: &HeaderMap, config: &Cli) -> Option<Response> {
+    let token = config.admin_token.as_deref().unwrap_or_default();
+    let bearer = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    // Digests compared rather than the tokens, so the time taken tells nothing about the token
+    if bearer.map(|bearer| Sha256::digest(bearer.trim())) != Some(Sha256::digest(token)) {
+        return Some(
+            (
+                StatusCode::UNAUTHORIZED,
+                AppendHeaders([(header::WWW_AUTHENTICATE, "Bearer")]),
+                "Invalid admin token",
+            )
+                .into_response(),
+        );
+    }
+
+    None
+}
+
+/// Hit rate and contents of the cache of outputs
+async fn cache_stats_handler(
+    Extension(config): Extension<Cli>,
+    Extension(cache): Extension<Arc<Cache>>,
+    headers: HeaderMap,
+) -> Result<Json<CacheStats>, Response> {
+    if let Some(rejection) = admin_rejection(&headers, &config) {
+        return Err(rejection);
+    }
+    Ok(Json(cache.stats().await))
+}
+
+#[derive(Debug, Serialize)]
+struct PurgeResponse {
+    /// Cached outputs removed
+    purged: usize,
+}
+
+/// Remove every cached output of the source at the path, whatever its size or format
+async fn purge_handler(
+    Extension(config): Extension<Cli>,
+    Extension(cache): Extension<Arc<Cache>>,
+    uri: Uri,
+    headers: HeaderMap,
+) -> Result<Json<PurgeResponse>, Response> {
+    if let Some(rejection) = admin_rejection(&headers, &config) {
+        return Err(rejection);
+    }
+    let path = percent_encoding::percent_decode_str(uri.path())
+        .decode_utf8()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Path is not valid UTF-8").into_response())?
+        .into_owned();
+    let purged = cache.purge(&path).await;
+    tracing::info!(path, purged, "Cache purged");
+    metrics::counter!("image_resize_cache_purged_total").increment(purged as u64);
+
+    Ok(Json(PurgeResponse { purged }))
 }
 
+/// Variants of a single batch, each one is resized and encoded
+const MAX_BATCH_VARIANTS: usize = 16;
+/// Bytes of the JSON body of a batch
+const MAX_BATCH_BODY: u64 = 64 * 1024;
+
+/// One source and the outputs wanted from it
 #[derive(Debug, Deserialize)]
-struct Params {
-    width: Option<NonZeroU32>,
-    height: Option<NonZeroU32>,
-    w: Option<NonZeroU32>,
-    h: Option<NonZeroU32>,
+struct BatchRequest {
+    path: String,
+    /// Each with the parameters of an image URL
+    variants: Vec<Params>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    variants: Vec<BatchVariant>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchVariant {
+    content_type: &'static str,
+    width: u32,
+    height: u32,
+    /// Base64 encoded output
+    data: String,
+}
+
+/// Produce several outputs of one source, fetching and decoding it only once
+async fn batch_handler(
+    Extension(config): Extension<Cli>,
+    Extension(sources): Extension<Arc<SourceChain>>,
+    Extension(cache): Extension<Arc<Cache>>,
+    Extension(permits): Extension<Option<Arc<Semaphore>>>,
+    headers: HeaderMap,
+    ContentLengthLimit(Json(request)): ContentLengthLimit<Json<BatchRequest>, MAX_BATCH_BODY>,
+) -> Result<Response, Response> {
+    let start = Instant::now();
+    metrics::counter!("image_resize_batch_requests_total").increment(1);
+    let ages = CacheAges::new(&config);
+    // Signatures cover image URLs, a batch could request any of their outputs unsigned
+    if config.signing_secret.is_some() {
+        return Err((StatusCode::FORBIDDEN, "Batch requests can't be signed").into_response());
+    }
+
+    let BatchRequest { path, variants } = request;
+    if variants.is_empty() || variants.len() > MAX_BATCH_VARIANTS {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("A batch must have between 1 and {MAX_BATCH_VARIANTS} variants"),
+        )
+            .into_response());
+    }
+    for variant in &variants {
+        check_params(variant, &config).map_err(|err| err.response(ages))?;
+        let summary = variant.blurhash == Some(true) || variant.color.is_some() || variant.info == Some(true);
+        if summary || variant.srcset.is_some() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Placeholders, infos and srcset manifests can't be batched",
+            )
+                .into_response());
+        }
+    }
+    // Sources are looked up by the path of image URLs
+    let path = if path.starts_with('/') {
+        path
+    } else {
+        format!("/{path}")
+    };
+
+    let (
+        Fetched {
+            data: bytes,
+            cache_policy,
+            ..
+        },
+        fallback,
+    ) = fetch_source(&path, &headers, &sources, &config).await?;
+    let time_fetch = start.elapsed();
+    let cache_control = ages.header(if fallback {
+        Cached::Fallback
+    } else {
+        Cached::Success(cache_policy)
+    });
+
+    let process_timeout = Duration::from_secs(config.process_timeout);
+    let count = variants.len();
+    let (time_decode, outputs) = run_blocking(&path, process_timeout, ages, permits.as_ref(), {
+        let path = path.clone();
+        move || {
+            let start = Instant::now();
+            let src_image = to_fir_image(&decode(&path, &bytes, &decode_limits(&config))?);
+            let time_decode = start.elapsed();
+
+            let outputs = variants
+                .iter()
+                .map(|params| {
+                    let options = resize_options(params, &config);
+                    let format = params.format().or(config.default_format);
+                    let key = cache.key(&path, params, format);
+                    transform(&path, &bytes, &src_image, &options, params, format, Some(&key), &config)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok((time_decode, outputs))
+        }
+    })
+    .await?;
+
+    tracing::info!(
+        path,
+        variants = count,
+        fetch_ms = time_fetch.as_millis() as u64,
+        decode_ms = time_decode.as_millis() as u64,
+        total_ms = start.elapsed().as_millis() as u64,
+        "Batch processed"
+    );
+    metrics::histogram!("image_resize_fetch_seconds").record(time_fetch);
+    metrics::histogram!("image_resize_decode_seconds").record(time_decode);
+
+    let variants = outputs
+        .into_iter()
+        .map(|processed| {
+            metrics::histogram!("image_resize_resize_seconds").record(processed.time_resize);
+            metrics::histogram!("image_resize_encode_seconds").record(processed.time_encode);
+            BatchVariant {
+                content_type: processed.format.content_type(),
+                width: processed.resized.0.get(),
+                height: processed.resized.1.get(),
+                data: BASE64_STANDARD.encode(&processed.data),
+            }
+        })
+        .collect();
+
+    Ok((
+        AppendHeaders([(header::CACHE_CONTROL, cache_control)]),
+        Json(BatchResponse { variants }),
+    )
+        .into_response())
+}
+
+/// Tiles of a single sprite sheet
+const MAX_SPRITE_TILES: usize = 256;
+/// Sources fetched at once for a sprite sheet
+const SPRITE_FETCH_CONCURRENCY: usize = 8;
+
+/// Sources and layout of a sprite sheet
+#[derive(Debug, Deserialize)]
+struct SpriteRequest {
+    /// In the order of the cells, row after row
+    paths: Vec<String>,
+    /// Cells per row, a single row when absent
+    columns: Option<NonZeroU32>,
+    /// Pixels between neighboring cells
+    #[serde(default)]
+    gutter: u32,
+    /// Parameters of an image URL applied to every tile, `width` and `height` being the size they fit in
+    tile: Params,
+}
+
+#[derive(Debug, Serialize)]
+struct SpriteResponse {
+    content_type: &'static str,
+    width: u32,
+    height: u32,
+    /// Base64 encoded sheet
+    data: String,
+    /// Position of every source in the sheet, in the order of the request
+    tiles: Vec<SpriteTile>,
+}
+
+#[derive(Debug, Serialize)]
+struct SpriteTile {
+    path: String,
+    #[serde(flatten)]
+    placed: sprite::Placed,
+}
+
+/// Resize several sources to a common size and composite them into a single sprite sheet
+async fn sprite_handler(
+    Extension(config): Extension<Cli>,
+    Extension(sources): Extension<Arc<SourceChain>>,
+    Extension(permits): Extension<Option<Arc<Semaphore>>>,
+    headers: HeaderMap,
+    ContentLengthLimit(Json(request)): ContentLengthLimit<Json<SpriteRequest>, MAX_BATCH_BODY>,
+) -> Result<Response, Response> {
+    let start = Instant::now();
+    metrics::counter!("image_resize_sprite_requests_total").increment(1);
+    let ages = CacheAges::new(&config);
+    // Signatures cover image URLs, a sprite could gather any of their outputs unsigned
+    if config.signing_secret.is_some() {
+        return Err((StatusCode::FORBIDDEN, "Sprite requests can't be signed").into_response());
+    }
+
+    let SpriteRequest {
+        paths,
+        columns,
+        gutter,
+        tile,
+    } = request;
+    if paths.is_empty() || paths.len() > MAX_SPRITE_TILES {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("A sprite must have between 1 and {MAX_SPRITE_TILES} tiles"),
+        )
+            .into_response());
+    }
+    check_params(&tile, &config).map_err(|err| err.response(ages))?;
+    let summary = tile.blurhash == Some(true) || tile.color.is_some() || tile.info == Some(true);
+    if summary || tile.srcset.is_some() || tile.depth == Some(BitDepth::Sixteen) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Placeholders, infos, srcset manifests and 16-bit outputs can't be sprites",
+        )
+            .into_response());
+    }
+    // Tiles are padded to the requested box, the cells are that box once rotated
+    let (Some(cell_width), Some(cell_height)) = resize_options(&tile, &config).requested() else {
+        return Err((StatusCode::BAD_REQUEST, "Sprite tiles need a width and a height").into_response());
+    };
+    let (cell_width, cell_height) = match tile.rotate {
+        Some(Rotation::Rotate90 | Rotation::Rotate270) => (cell_height, cell_width),
+        _ => (cell_width, cell_height),
+    };
+    let layout = sprite::Layout {
+        columns: columns.unwrap_or(NonZeroU32::new(paths.len() as u32).unwrap()),
+        gutter,
+        cell_width,
+        cell_height,
+    };
+    // Sources are looked up by the path of image URLs
+    let paths = paths
+        .into_iter()
+        .map(|path| {
+            if path.starts_with('/') {
+                path
+            } else {
+                format!("/{path}")
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let permits_fetch = Arc::new(Semaphore::new(SPRITE_FETCH_CONCURRENCY));
+    let mut fetches = tokio::task::JoinSet::new();
+    for (index, path) in paths.iter().cloned().enumerate() {
+        let (headers, sources, config) = (headers.clone(), sources.clone(), config.clone());
+        let permits_fetch = permits_fetch.clone();
+        fetches.spawn(async move {
+            let _permit = permits_fetch
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            (index, fetch_source(&path, &headers, &sources, &config).await)
+        });
+    }
+    let mut fetched = Vec::with_capacity(paths.len());
+    fetched.resize_with(paths.len(), || None);
+    let mut any_fallback = false;
+    while let Some(result) = fetches.join_next().await {
+        let (index, result) = result.map_err(|err| {
+            tracing::error!("Fetch sprite tile task error {err:#}");
+            (StatusCode::INTERNAL_SERVER_ERROR, "Fetch image error").into_response()
+        })?;
+        let (Fetched { data, .. }, fallback) = result?;
+        any_fallback |= fallback;
+        fetched[index] = Some(data);
+    }
+    let fetched = fetched.into_iter().map(Option::unwrap).collect::<Vec<_>>();
+    let time_fetch = start.elapsed();
+    // The origin policies of the tiles don't add up, the sheet gets the default age
+    let cache_control = ages.header(if any_fallback {
+        Cached::Fallback
+    } else {
+        Cached::Success(None)
+    });
+
+    let process_timeout = Duration::from_secs(config.process_timeout);
+    let count = paths.len();
+    let (format, (width, height), data, placed) = run_blocking(&paths[0], process_timeout, ages, permits.as_ref(), {
+        let paths = paths.clone();
+        move || {
+            let mut options = resize_options(&tile, &config);
+            options.fit.get_or_insert(FitMode::Contain);
+            options.pad = true;
+            let tiles = paths
+                .iter()
+                .zip(&fetched)
+                .map(|(path, bytes)| {
+                    let src_image = to_fir_image(&decode(path, bytes, &decode_limits(&config))?);
+                    render(path, &src_image, &options, &tile, &config)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if let Some(tile) = tiles
+                .iter()
+                .find(|tile| !layout.fits(tile.width().get(), tile.height().get()))
+            {
+                return Err(ProcessError::Invalid(format!(
+                    "Sprite tile {}x{} doesn't fit in cells of {}x{}",
+                    tile.width(),
+                    tile.height(),
+                    layout.cell_width,
+                    layout.cell_height
+                )));
+            }
+            let (width, height) = layout.size(tiles.len() as u32);
+            let limits = limits(&config);
+            if width > limits.max_width as u64
+                || height > limits.max_height as u64
+                || width * height > limits.max_pixels
+            {
+                return Err(ProcessError::Invalid(format!(
+                    "Sprite {width}x{height} exceeds the maximum of {}x{} and {} pixels",
+                    limits.max_width, limits.max_height, limits.max_pixels
+                )));
+            }
+
+            let (sheet, placed) = sprite::compose(&layout, &tiles);
+            let (format, _, data) = encode_output(&paths[0], &[], sheet, &tile, tile.format(), None, &config)?;
+            Ok((format, (width as u32, height as u32), data, placed))
+        }
+    })
+    .await?;
+
+    tracing::info!(
+        tiles = count,
+        fetch_ms = time_fetch.as_millis() as u64,
+        total_ms = start.elapsed().as_millis() as u64,
+        "Sprite processed"
+    );
+    metrics::histogram!("image_resize_fetch_seconds").record(time_fetch);
+
+    let tiles = paths
+        .into_iter()
+        .zip(placed)
+        .map(|(path, placed)| SpriteTile { path, placed })
+        .collect();
+
+    Ok((
+        AppendHeaders([(header::CACHE_CONTROL, cache_control)]),
+        Json(SpriteResponse {
+            content_type: format.content_type(),
+            width,
+            height,
+            data: BASE64_STANDARD.encode(&data),
+            tiles,
+        }),
+    )
+        .into_response())
+}
+
+/// Reject out of range parameters, before anything is fetched
+fn check_params(params: &Params, config: &Cli) -> Result<(), ProcessError> {
+    if matches!(params.quality(), Some(Quality::Fixed(quality)) if !(1..=100).contains(&quality)) {
+        return Err(ProcessError::Invalid("Quality must be between 1 and 100".to_string()));
+    }
+    if let Some(format) = params.format().filter(|format| !format.is_available()) {
+        return Err(ProcessError::Invalid(format!(
+            "format={} isn't available in this build",
+            format.extension()
+        )));
+    }
+    if params.speed.is_some_and(|speed| !(1..=10).contains(&speed)) {
+        return Err(ProcessError::Invalid("Speed must be between 1 and 10".to_string()));
+    }
+    if params.lossless == Some(true) && params.format().or(config.default_format) != Some(OutputFormat::Webp) {
+        return Err(ProcessError::Invalid(
+            "Lossless is only supported with format=webp".to_string(),
+        ));
+    }
+    if params.depth == Some(BitDepth::Sixteen) && params.format().or(config.default_format) != Some(OutputFormat::Png) {
+        return Err(ProcessError::Invalid(
+            "16-bit depth is only supported with format=png".to_string(),
+        ));
+    }
+    if params.blur.is_some_and(|blur| !(0.0..=MAX_BLUR).contains(&blur)) {
+        return Err(ProcessError::Invalid(format!("Blur must be between 0 and {MAX_BLUR}")));
+    }
+    if params
+        .trim_tolerance
+        .is_some_and(|tolerance| !(0.0..=100.0).contains(&tolerance))
+    {
+        return Err(ProcessError::Invalid(
+            "Trim tolerance must be between 0 and 100".to_string(),
+        ));
+    }
+    if params
+        .watermark_opacity
+        .is_some_and(|opacity| !(0.0..=1.0).contains(&opacity))
+    {
+        return Err(ProcessError::Invalid(
+            "Watermark opacity must be between 0 and 1".to_string(),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Headers of the client request among `--forward-headers`, for the origins
+fn forwarded_headers(headers: &HeaderMap, config: &Cli) -> HeaderMap {
+    let names = config.forward_headers.as_deref().unwrap_or_default();
+    headers
+        .iter()
+        .filter(|(name, _)| names.contains(name))
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect()
+}
+
+/// Fetch the source through the chain, or the fallback image in place of a missing one (the `bool` is then true)
+async fn fetch_source(
+    path: &str,
+    headers: &HeaderMap,
+    sources: &SourceChain,
+    config: &Cli,
+) -> Result<(Fetched, bool), Response> {
+    let forwarded = forwarded_headers(headers, config);
+    // The timeout covers the whole chain of sources
+    let fetch_timeout = Duration::from_secs(config.fetch_timeout);
+    let err = match tokio::time::timeout(fetch_timeout, sources.fetch(path, &forwarded)).await {
+        Ok(Ok(fetched)) => return Ok((fetched, false)),
+        Ok(Err(FetchError::Forbidden)) => {
+            return Err((StatusCode::FORBIDDEN, "Source host not allowed").into_response())
+        }
+        Ok(Err(FetchError::TooLarge)) => {
+            return Err((StatusCode::PAYLOAD_TOO_LARGE, "Source image too large").into_response())
+        }
+        Ok(Err(err)) => err,
+        Err(_) => {
+            tracing::info!(path, "Fetch timed out");
+            metrics::counter!("image_resize_upstream_errors_total").increment(1);
+            FetchError::UpstreamTimeout
+        }
+    };
+
+    // Serve the fallback in place of a missing or unavailable source, it must not stick around in caches once the
+    // source is back
+    if let Some(fallback_path) = &config.fallback_image {
+        match tokio::fs::read(fallback_path).await {
+            Ok(data) => {
+                metrics::counter!("image_resize_fallbacks_total").increment(1);
+                return Ok((Fetched::new(Bytes::from(data)), true));
+            }
+            Err(err) => tracing::error!(path, "Read fallback image error {err:#}"),
+        }
+    }
+
+    // Origin failures are told apart from missing sources, and aren't cached
+    Err(match err {
+        FetchError::UpstreamTimeout | FetchError::Upstream(Some(StatusCode::GATEWAY_TIMEOUT)) => {
+            (StatusCode::GATEWAY_TIMEOUT, "Fetch image timed out").into_response()
+        }
+        FetchError::Upstream(Some(status)) => {
+            (StatusCode::BAD_GATEWAY, format!("Origin answered {status}")).into_response()
+        }
+        FetchError::Upstream(None) => (StatusCode::BAD_GATEWAY, "Origin unreachable").into_response(),
+        _ => (
+            StatusCode::NOT_FOUND,
+            AppendHeaders([(header::CACHE_CONTROL, CacheAges::new(config).header(Cached::NotFound))]),
+        )
+            .into_response(),
+    })
+}
+
+/// Output of the decode, resize and encode stages
+struct Processed {
+    data: Vec<u8>,
+    format: OutputFormat,
+    quality: u8,
+    filter: ResizeFilter,
+    original: (NonZeroU32, NonZeroU32),
+    resized: (NonZeroU32, NonZeroU32),
+    time_decode: Duration,
+    time_resize: Duration,
+    time_encode: Duration,
+}
+
+/// Summary of a source, answered instead of the image by `info=true`
+#[derive(Debug, Serialize)]
+struct SourceInfo {
+    /// Dimensions of the source, upright
+    width: u32,
+    height: u32,
+    format: &'static str,
+    /// Size of the encoded source
+    bytes: usize,
+}
+
+/// Source decoded, or rendered, for the output
+struct Decoded {
+    image: DynamicImage,
+    /// Size of the source, larger than the image when it was decoded or rendered downscaled
+    original: (u32, u32),
+}
+
+enum ProcessError {
+    /// Not an image, or a format or feature of it that can't be decoded
+    Unsupported,
+    /// Image that failed to decode, possibly truncated or corrupted on the way
+    Decode,
+    /// Requested transformation rejected for this source
+    Invalid(String),
+    /// Source over `--max-source-pixels`
+    TooLarge(String),
+    /// Source still decoding after `--decode-timeout`
+    DecodeTimeout,
+    Resize,
+    Encode,
+}
+
+impl ProcessError {
+    fn response(self, ages: CacheAges) -> Response {
+        let (status, cached, message) = match self {
+            ProcessError::Invalid(err) => return (StatusCode::BAD_REQUEST, err).into_response(),
+            ProcessError::TooLarge(err) => return (StatusCode::PAYLOAD_TOO_LARGE, err).into_response(),
+            ProcessError::Unsupported => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                Cached::Unsupported,
+                "Unsupported image format",
+            ),
+            ProcessError::Decode => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Cached::DecodeError,
+                "Decode image error",
+            ),
+            ProcessError::DecodeTimeout => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Cached::DecodeError,
+                "Decode image timed out",
+            ),
+            ProcessError::Resize => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Cached::ProcessError,
+                "Resize image error",
+            ),
+            ProcessError::Encode => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Cached::ProcessError,
+                "Encode image error",
+            ),
+        };
+
+        (
+            status,
+            AppendHeaders([(header::CACHE_CONTROL, ages.header(cached))]),
+            message,
+        )
+            .into_response()
+    }
+}
+
+/// Decode the source and turn it upright, SVG sources and the first page of PDF sources are rendered at their own
+/// size within `max_render_size`
+fn decode(path: &str, bytes: &[u8], decode_limits: &DecodeLimits) -> Result<DynamicImage, ProcessError> {
+    let max_render_size = decode_limits.max_render_size;
+    if svg::is_svg(bytes) {
+        let tree = parse_svg(path, bytes)?;
+        let (width, height) = svg::size(&tree);
+        let scale = f32::min(1.0, max_render_size as f32 / width.max(height) as f32);
+        return svg::rasterize(&tree, scale, None).ok_or(ProcessError::Decode);
+    }
+    #[cfg(feature = "pdf")]
+    if pdf::is_pdf(bytes) {
+        let page = pdf::Page::open(bytes, 1).map_err(|err| pdf_error(path, err))?;
+        let (width, height) = page.size().map_err(|err| pdf_error(path, err))?;
+        let scale = f32::min(1.0, max_render_size as f32 / width.max(height) as f32);
+        let (width, height) = scaled(width, height, scale);
+        return page.render(width, height).map_err(|err| pdf_error(path, err));
+    }
+
+    check_source_pixels(path, bytes, decode_limits.max_source_pixels)?;
+    let image = with_decode_timeout(path, decode_limits.timeout, {
+        let (path, bytes) = (path.to_owned(), bytes.to_vec());
+        move || {
+            image::io::Reader::new(Cursor::new(bytes))
+                .with_guessed_format()
+                .map_err(image::ImageError::IoError)
+                .and_then(|reader| reader.decode())
+                .map_err(|err| image_error(&path, err))
+        }
+    })?;
+
+    Ok(match metadata::exif_orientation(bytes) {
+        Some(orientation) => metadata::apply_orientation(image, orientation),
+        None => image,
+    })
+}
+
+/// Bounds of the work of decoding a source
+#[derive(Debug, Clone, Copy)]
+struct DecodeLimits {
+    max_render_size: u32,
+    max_source_pixels: u64,
+    timeout: Duration,
+}
+
+fn decode_limits(config: &Cli) -> DecodeLimits {
+    DecodeLimits {
+        max_render_size: config.max_render_size,
+        max_source_pixels: config.max_source_pixels,
+        timeout: Duration::from_secs(config.decode_timeout),
+    }
+}
+
+/// Run the raster `decode` on a blocking task of its own, abandoned after `timeout` so a malformed source making the
+/// decoder spin can't hold the request and its processing slot. The thread is only freed once the decoder returns.
+/// Called from the blocking thread pool, which can wait on the runtime.
+fn with_decode_timeout<T: Send + 'static>(
+    path: &str,
+    timeout: Duration,
+    decode: impl FnOnce() -> Result<T, ProcessError> + Send + 'static,
+) -> Result<T, ProcessError> {
+    let span = tracing::Span::current();
+    let task = tokio::task::spawn_blocking(move || {
+        let _span = span.enter();
+        decode()
+    });
+    match tokio::runtime::Handle::current().block_on(tokio::time::timeout(timeout, task)) {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => {
+            tracing::error!(path, "Decode image task error {err:#}");
+            Err(ProcessError::Decode)
+        }
+        Err(_) => {
+            tracing::warn!(path, timeout_s = timeout.as_secs(), "Decode image timed out");
+            metrics::counter!("image_resize_decode_timeouts_total").increment(1);
+            Err(ProcessError::DecodeTimeout)
+        }
+    }
+}
+
+/// Refuse raster sources with more than `max_source_pixels`, whatever the output size. The header tells the size of
+/// the decoded pixels before any of them is allocated, tiny files can claim huge ones.
+fn check_source_pixels(path: &str, bytes: &[u8], max_source_pixels: u64) -> Result<(), ProcessError> {
+    let Ok((width, height)) = image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(image::ImageError::IoError)
+        .and_then(|reader| reader.into_dimensions())
+    else {
+        // Vector sources are rendered within `--max-render-size`, decoding fails the others
+        return Ok(());
+    };
+    if width as u64 * height as u64 > max_source_pixels {
+        tracing::info!(path, width, height, "Source too large to decode");
+        return Err(ProcessError::TooLarge(format!(
+            "Source is {width}x{height}, larger than {max_source_pixels} pixels"
+        )));
+    }
+
+    Ok(())
+}
+
+/// Size of the upright source, read from its header without decoding it
+#[cfg_attr(not(feature = "pdf"), allow(unused_variables))]
+fn source_size(path: &str, bytes: &[u8], params: &Params) -> Result<(u32, u32), ProcessError> {
+    if svg::is_svg(bytes) {
+        return Ok(svg::size(&parse_svg(path, bytes)?));
+    }
+    #[cfg(feature = "pdf")]
+    if pdf::is_pdf(bytes) {
+        let page = params.page.map_or(1, std::num::NonZeroU16::get);
+        let page = pdf::Page::open(bytes, page).map_err(|err| pdf_error(path, err))?;
+        return page.size().map_err(|err| pdf_error(path, err));
+    }
+
+    let (width, height) = image::io::Reader::new(Cursor::new(bytes))
+        .with_guessed_format()
+        .map_err(image::ImageError::IoError)
+        .and_then(|reader| reader.into_dimensions())
+        .map_err(|err| image_error(path, err))?;
+    // Orientations 5 to 8 turn the image by a quarter
+    Ok(match metadata::exif_orientation(bytes) {
+        Some(orientation) if orientation >= 5 => (height, width),
+        _ => (width, height),
+    })
+}
+
+/// Read the format and dimensions of the source from its header, without decoding it
+fn source_info(path: &str, bytes: &[u8], params: &Params) -> Result<SourceInfo, ProcessError> {
+    let (width, height) = source_size(path, bytes, params)?;
+    let format = match image::guess_format(bytes) {
+        Ok(format) => format.extensions_str()[0],
+        // Only vector sources have a size without a raster format
+        Err(_) if svg::is_svg(bytes) => "svg",
+        Err(_) => "pdf",
+    };
+
+    Ok(SourceInfo {
+        width,
+        height,
+        format,
+        bytes: bytes.len(),
+    })
+}
+
+/// Decode the source, or render vector ones (SVG, PDF) at the output size since they have no pixels of their own
+#[cfg_attr(not(feature = "pdf"), allow(unused_variables))]
+fn decode_for_output(
+    path: &str,
+    bytes: &[u8],
+    params: &Params,
+    options: &mut ResizeOptions,
+    limits: &Limits,
+    decode_limits: &DecodeLimits,
+) -> Result<Decoded, ProcessError> {
+    let max_render_size = decode_limits.max_render_size;
+    if svg::is_svg(bytes) {
+        let tree = parse_svg(path, bytes)?;
+        let (width, height) = svg::size(&tree);
+        let (scale, crop) = plan_render(width, height, options, limits)?;
+        let (render_width, render_height) = svg::render_size(&tree, scale, crop);
+        check_render_size(render_width, render_height, max_render_size)?;
+        let image = svg::rasterize(&tree, scale, crop).ok_or(ProcessError::Decode)?;
+        return Ok(Decoded {
+            image,
+            original: (width, height),
+        });
+    }
+    #[cfg(feature = "pdf")]
+    if pdf::is_pdf(bytes) {
+        let page = params.page.map_or(1, std::num::NonZeroU16::get);
+        let page = pdf::Page::open(bytes, page).map_err(|err| pdf_error(path, err))?;
+        let (width, height) = page.size().map_err(|err| pdf_error(path, err))?;
+        let (scale, crop) = plan_render(width, height, options, limits)?;
+        // Pdfium renders whole pages, the crop is cut out of it afterwards
+        let (render_width, render_height) = scaled(width, height, scale);
+        check_render_size(render_width, render_height, max_render_size)?;
+        let image = page
+            .render(render_width, render_height)
+            .map_err(|err| pdf_error(path, err))?;
+
+        let image = match crop {
+            Some(crop) => {
+                let (width, height) = scaled(crop.width.get(), crop.height.get(), scale);
+                let (left, top) = (crop.left as f32 * scale, crop.top as f32 * scale);
+                image.crop_imm(left.round() as u32, top.round() as u32, width, height)
+            }
+            None => image,
+        };
+        return Ok(Decoded {
+            image,
+            original: (width, height),
+        });
+    }
+    if let Some(decoded) = decode_jpeg_downscaled(path, bytes, options, limits, decode_limits.timeout)? {
+        return Ok(decoded);
+    }
+
+    let image = decode(path, bytes, decode_limits)?;
+    Ok(Decoded {
+        original: (image.width(), image.height()),
+        image,
+    })
+}
+
+/// `415` for sources `image` doesn't know how to decode, the others failed decoding
+fn image_error(path: &str, err: image::ImageError) -> ProcessError {
+    match err {
+        image::ImageError::Unsupported(err) => {
+            tracing::info!(path, "Unsupported image {err}");
+            ProcessError::Unsupported
+        }
+        err => {
+            tracing::error!(path, "Decode image error {err:#}");
+            ProcessError::Decode
+        }
+    }
+}
+
+fn parse_svg(path: &str, bytes: &[u8]) -> Result<resvg::usvg::Tree, ProcessError> {
+    svg::parse(bytes).map_err(|err| {
+        tracing::error!(path, "Parse SVG error {err:#}");
+        ProcessError::Decode
+    })
+}
+
+#[cfg(feature = "pdf")]
+fn pdf_error(path: &str, err: pdf::PdfError) -> ProcessError {
+    match err {
+        pdf::PdfError::PageOutOfRange(..) => ProcessError::Invalid(err.to_string()),
+        _ => {
+            tracing::error!(path, "Render PDF error {err}");
+            ProcessError::Decode
+        }
+    }
+}
+
+/// Plan the render of a vector source whose own size is `width`x`height`, returning the scale to render it at and
+/// the region of it to render.
+///
+/// The crop is then removed from `options` so the render is only resized by the rounding between it and the
+/// planned output.
+fn plan_render(
+    width: u32,
+    height: u32,
+    options: &mut ResizeOptions,
+    limits: &Limits,
+) -> Result<(f32, Option<fir::CropBox>), ProcessError> {
+    // Vector sources lose nothing when scaled up
+    let vector_options = ResizeOptions {
+        no_upscale: false,
+        ..*options
+    };
+    let plan = resize::plan(
+        NonZeroU32::new(width).unwrap(),
+        NonZeroU32::new(height).unwrap(),
+        &vector_options,
+    )
+    .and_then(|plan| plan.check(limits).map(|()| plan))
+    .map_err(ProcessError::Invalid)?;
+
+    // `fill` stretches one of the dimensions, render at the larger scale and let the resize shrink the other one
+    let (region_width, region_height) = plan
+        .crop
+        .map_or((width, height), |crop| (crop.width.get(), crop.height.get()));
+    let scale = f32::max(
+        plan.width.get() as f32 / region_width as f32,
+        plan.height.get() as f32 / region_height as f32,
+    );
+
+    // The render is already of the planned region, vector sources aren't trimmed
+    (options.crop, options.trim) = (None, None);
+    keep_planned_size(options, &plan);
+
+    Ok((scale, plan.crop))
+}
+
+/// Request the planned size explicitly, a source already decoded or rendered at the default size must not be
+/// scaled by `--default-scale` again
+fn keep_planned_size(options: &mut ResizeOptions, plan: &ResizePlan) {
+    if options.width.is_none() && options.height.is_none() {
+        (options.width, options.height, options.dpr) = (Some(plan.width), Some(plan.height), None);
+    }
+}
+
+/// Decode JPEG sources at the smallest DCT scale (1/8, 1/4 or 1/2) still covering the planned output, which skips
+/// most of the decoding work for thumbnails of large photos. `None` when the whole source is needed.
+fn decode_jpeg_downscaled(
+    path: &str,
+    bytes: &[u8],
+    options: &mut ResizeOptions,
+    limits: &Limits,
+    timeout: Duration,
+) -> Result<Option<Decoded>, ProcessError> {
+    // A requested crop is in source pixels, and the trimmed region is only known once decoded
+    if options.crop.is_some() || options.trim.is_some() || image::guess_format(bytes).ok() != Some(ImageFormat::Jpeg) {
+        return Ok(None);
+    }
+
+    let decode_error = |err| image_error(path, err);
+    let mut decoder = JpegDecoder::new(Cursor::new(bytes.to_vec())).map_err(decode_error)?;
+    let (width, height) = decoder.dimensions();
+    let orientation = metadata::exif_orientation(bytes);
+    // Orientations 5 to 8 turn the image by a quarter
+    let transposed = orientation.is_some_and(|orientation| orientation >= 5);
+    let (src_width, src_height) = if transposed { (height, width) } else { (width, height) };
+
+    let plan = resize::plan(
+        NonZeroU32::new(src_width).unwrap(),
+        NonZeroU32::new(src_height).unwrap(),
+        options,
+    )
+    .and_then(|plan| plan.check(limits).map(|()| plan))
+    .map_err(ProcessError::Invalid)?;
+    let (region_width, region_height) = plan
+        .crop
+        .map_or((src_width, src_height), |crop| (crop.width.get(), crop.height.get()));
+    let min_width = (src_width as u64 * plan.width.get() as u64).div_ceil(region_width as u64) as u32;
+    let min_height = (src_height as u64 * plan.height.get() as u64).div_ceil(region_height as u64) as u32;
+    let (min_width, min_height) = if transposed {
+        (min_height, min_width)
+    } else {
+        (min_width, min_height)
+    };
+
+    // Size of the output of the IDCT reduced to `size`x`size` from 8x8
+    let scaled = |len: u32, size: u32| (len * size).div_ceil(8);
+    let Some(size) = [1, 2, 4]
+        .into_iter()
+        .find(|&size| scaled(width, size) >= min_width && scaled(height, size) >= min_height)
+    else {
+        return Ok(None);
+    };
+    decoder
+        .scale(scaled(width, size) as u16, scaled(height, size) as u16)
+        .map_err(decode_error)?;
+    let image = with_decode_timeout(path, timeout, {
+        let path = path.to_owned();
+        move || DynamicImage::from_decoder(decoder).map_err(|err| image_error(&path, err))
+    })?;
+    tracing::debug!(path, scale = %format_args!("1/{}", 8 / size), "Decoded downscaled JPEG");
+
+    let image = match orientation {
+        Some(orientation) => metadata::apply_orientation(image, orientation),
+        None => image,
+    };
+    keep_planned_size(options, &plan);
+
+    Ok(Some(Decoded {
+        image,
+        original: (src_width, src_height),
+    }))
+}
+
+fn check_render_size(width: u32, height: u32, max_size: u32) -> Result<(), ProcessError> {
+    if width > max_size || height > max_size {
+        return Err(ProcessError::Invalid(format!(
+            "Render {width}x{height} exceeds the maximum of {max_size}x{max_size}"
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(feature = "pdf")]
+fn scaled(width: u32, height: u32, scale: f32) -> (u32, u32) {
+    (
+        (width as f32 * scale).round().max(1.0) as u32,
+        (height as f32 * scale).round().max(1.0) as u32,
+    )
+}
+
+/// Run CPU bound work on the blocking thread pool once one of the `permits` is free, giving up on each after `timeout`
+async fn run_blocking<T: Send + 'static>(
+    path: &str,
+    timeout: Duration,
+    ages: CacheAges,
+    permits: Option<&Arc<Semaphore>>,
+    work: impl FnOnce() -> Result<T, ProcessError> + Send + 'static,
+) -> Result<T, Response> {
+    // The permit moves into the task, a timed out task still holds it until the work actually ends
+    let permit = match permits {
+        Some(permits) => match tokio::time::timeout(timeout, permits.clone().acquire_owned()).await {
+            Ok(permit) => Some(permit.expect("the semaphore is never closed")),
+            Err(_) => {
+                tracing::warn!(path, "No processing slot freed up in time");
+                metrics::counter!("image_resize_busy_total").increment(1);
+                return Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    AppendHeaders([(header::RETRY_AFTER, "1")]),
+                    "Server busy",
+                )
+                    .into_response());
+            }
+        },
+        None => None,
+    };
+    let span = tracing::Span::current();
+    let work = move || {
+        let _permit = permit;
+        let _span = span.enter();
+        work()
+    };
+
+    match tokio::time::timeout(timeout, tokio::task::spawn_blocking(work)).await {
+        Ok(Ok(result)) => result.map_err(|err| err.response(ages)),
+        Ok(Err(err)) => {
+            tracing::error!(path, "Process image task error {err:#}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Process image error").into_response())
+        }
+        Err(_) => {
+            tracing::error!(path, "Process image timed out");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Process image timed out").into_response())
+        }
+    }
+}
+
+/// Decode, resize and encode the source, CPU bound so it runs on the blocking thread pool
+fn process(
+    path: &str,
+    bytes: &[u8],
+    params: &Params,
+    format: Option<OutputFormat>,
+    key: &str,
+    config: &Cli,
+) -> Result<Processed, ProcessError> {
+    let start = Instant::now();
+    let mut options = resize_options(params, config);
+    let limits = limits(config);
+
+    // Animations stay animated when they are kept as, or explicitly converted to, a format able to store them
+    let animated_format = params
+        .format()
+        .or(config.default_format)
+        .or_else(|| {
+            image::guess_format(bytes)
+                .ok()
+                .and_then(OutputFormat::from_image_format)
+        })
+        .filter(|format| matches!(format, OutputFormat::Gif | OutputFormat::Webp));
+    check_source_pixels(path, bytes, config.max_source_pixels)?;
+    if let Some(format) = animated_format {
+        let animation = with_decode_timeout(path, decode_limits(config).timeout, {
+            let (path, bytes, max_frames) = (path.to_owned(), bytes.to_vec(), config.max_frames);
+            move || {
+                animation::decode(&bytes, max_frames).map_err(|err| match err {
+                    animation::DecodeError::Image(err) => {
+                        tracing::error!(path, "Decode animation error {err:#}");
+                        ProcessError::Decode
+                    }
+                    animation::DecodeError::TooManyFrames => {
+                        ProcessError::Invalid(format!("Animation has more than {max_frames} frames"))
+                    }
+                })
+            }
+        })?;
+
+        if let Some(animation) = animation {
+            let time_decode = start.elapsed();
+            let start = Instant::now();
+            let plan = resize::plan(animation.width(), animation.height(), &options)
+                .and_then(|plan| plan.check(&limits).map(|()| plan))
+                .map_err(ProcessError::Invalid)?;
+            let background = params.bg.unwrap_or(Color([0; 4]));
+            let filter = params.filter.unwrap_or(ResizeFilter::Lanczos3);
+            let mut resizer = fir::Resizer::new(filter.algorithm());
+            let mut resized = animation::resize(
+                &animation,
+                &plan,
+                &effects(params),
+                background,
+                params.linear != Some(false),
+                &mut resizer,
+            )
+            .map_err(|err| {
+                tracing::error!(path, "Resize animation error {err:#}");
+                ProcessError::Resize
+            })?;
+            if let Some(watermark) = &config.watermark {
+                let placement = watermark_placement(params, config);
+                resized.frames = resized
+                    .frames
+                    .into_iter()
+                    .map(|frame| AnimationFrame {
+                        image: watermark.apply(frame.image, &placement),
+                        ..frame
+                    })
+                    .collect();
+            }
+
+            let time_resize = start.elapsed();
+            let start = Instant::now();
+            let quality = output_quality(params, format, config);
+            let data =
+                format::encode_animation(format, quality, params.lossless == Some(true), &resized).map_err(|err| {
+                    tracing::error!(path, "Encode animation error {err:#}");
+                    ProcessError::Encode
+                })?;
+
+            return Ok(Processed {
+                data,
+                format,
+                quality,
+                filter,
+                original: (animation.width(), animation.height()),
+                resized: (resized.width(), resized.height()),
+                time_decode,
+                time_resize,
+                time_encode: start.elapsed(),
+            });
+        }
+    }
+
+    let Decoded { image, original } =
+        decode_for_output(path, bytes, params, &mut options, &limits, &decode_limits(config))?;
+    let src_image = to_fir_image(&image);
+    let time_decode = start.elapsed();
+
+    Ok(Processed {
+        original: (
+            NonZeroU32::new(original.0).unwrap(),
+            NonZeroU32::new(original.1).unwrap(),
+        ),
+        time_decode,
+        ..transform(path, bytes, &src_image, &options, params, format, Some(key), config)?
+    })
+}
+
+/// Quality of a `format` output: the requested one, else the default of the format, else `--default-quality`.
+/// `quality=auto` searches the quality of still JPEG and WebP outputs, the others get the default.
+fn output_quality(params: &Params, format: OutputFormat, config: &Cli) -> u8 {
+    let format_default = match format {
+        OutputFormat::Jpeg => config.jpeg_quality,
+        OutputFormat::Webp => config.webp_quality,
+        OutputFormat::Avif => config.avif_quality,
+        OutputFormat::Png | OutputFormat::Gif => None,
+    };
+
+    let requested = match params.quality() {
+        Some(Quality::Fixed(quality)) => Some(quality),
+        Some(Quality::Auto) | None => None,
+    };
+
+    requested.or(format_default).unwrap_or(config.default_quality)
+}
+
+fn resize_options(params: &Params, config: &Cli) -> ResizeOptions {
+    ResizeOptions {
+        width: params.width.or(params.w),
+        height: params.height.or(params.h),
+        fit: params.fit,
+        gravity: params.gravity,
+        focal: params.focal,
+        crop: params.crop,
+        dpr: params.dpr,
+        pad: params.bg.is_some(),
+        default_scale: Some(config.default_scale),
+        no_upscale: !params.upscale.unwrap_or(!config.no_upscale),
+        trim: (params.trim == Some(true)).then(|| params.trim_tolerance.unwrap_or(trim::DEFAULT_TOLERANCE)),
+    }
+}
+
+fn limits(config: &Cli) -> Limits {
+    Limits {
+        max_width: config.max_width,
+        max_height: config.max_height,
+        max_pixels: config.max_pixels,
+    }
+}
+
+fn watermark_placement(params: &Params, config: &Cli) -> Placement {
+    Placement {
+        gravity: params.watermark_gravity.unwrap_or(Gravity::Southeast),
+        opacity: params.watermark_opacity.unwrap_or(1.0),
+        size: config.watermark_size / 100.0,
+    }
+}
+
+fn effects(params: &Params) -> Effects {
+    Effects {
+        blur: params.blur.unwrap_or_default(),
+        sharpen: params
+            .sharpen
+            .filter(|sharpen| !sharpen.is_nan())
+            .map_or(0.0, |sharpen| sharpen.clamp(0.0, MAX_SHARPEN)),
+        color: params.effect,
+        rotate: params.rotate,
+        flip: params.flip,
+    }
+}
+
+/// Pixels of the decoded source in the layout of the resizer, keeping the alpha channel only when there is one
+fn to_fir_image(image: &DynamicImage) -> fir::Image<'static> {
+    let has_alpha = image.color().has_alpha();
+    // Deeper sources are resized in 16 bits, an 8-bit copy would band their gradients
+    let deep = image.color().bytes_per_pixel() > image.color().channel_count();
+    let (buffer, pixel_type) = match (deep, has_alpha) {
+        (true, true) => (depth::bytes(&image.to_rgba16()), fir::PixelType::U16x4),
+        (true, false) => (depth::bytes(&image.to_rgb16()), fir::PixelType::U16x3),
+        (false, true) => (image.to_rgba8().into_raw(), fir::PixelType::U8x4),
+        (false, false) => (image.to_rgb8().into_raw(), fir::PixelType::U8x3),
+    };
+
+    fir::Image::from_vec_u8(
+        NonZeroU32::new(image.width()).unwrap(),
+        NonZeroU32::new(image.height()).unwrap(),
+        buffer,
+        pixel_type,
+    )
+    .unwrap()
+}
+
+/// Resize, apply the effects to and encode the decoded source. The processed `original` size is the one of
+/// `src_image`, and the decode time zero.
+#[allow(clippy::too_many_arguments)]
+fn transform(
+    path: &str,
+    bytes: &[u8],
+    src_image: &fir::Image,
+    options: &ResizeOptions,
+    params: &Params,
+    format: Option<OutputFormat>,
+    key: Option<&str>,
+    config: &Cli,
+) -> Result<Processed, ProcessError> {
+    let start = Instant::now();
+    let filter = params.filter.unwrap_or(ResizeFilter::Lanczos3);
+    let dst_image = render(path, src_image, options, params, config)?;
+    let dst_image = match &config.watermark {
+        Some(watermark) => watermark.apply(dst_image, &watermark_placement(params, config)),
+        None => dst_image,
+    };
+    let resized = (dst_image.width(), dst_image.height());
+
+    let time_resize = start.elapsed();
+    let start = Instant::now();
+
+    let (format, quality, data) = encode_output(path, bytes, dst_image, params, format, key, config)?;
+
+    Ok(Processed {
+        data,
+        format,
+        quality,
+        filter,
+        original: (src_image.width(), src_image.height()),
+        resized,
+        time_decode: Duration::ZERO,
+        time_resize,
+        time_encode: start.elapsed(),
+    })
+}
+
+/// Pixels of the decoded source once trimmed, cropped, resized, padded and with the effects applied
+fn render(
+    path: &str,
+    src_image: &fir::Image,
+    options: &ResizeOptions,
+    params: &Params,
+    config: &Cli,
+) -> Result<fir::Image<'static>, ProcessError> {
+    let filter = params.filter.unwrap_or(ResizeFilter::Lanczos3);
+    let mut resizer = fir::Resizer::new(filter.algorithm());
+    let effects = effects(params);
+    // A focal point takes precedence over `gravity=smart`
+    let smart_crop = options.gravity == Some(Gravity::Smart) && options.focal.is_none();
+    // The trim and smart crop analyses look at 8-bit pixels
+    let analyzed = (options.trim.is_some() || smart_crop)
+        .then(|| depth::to_8_bit(src_image))
+        .flatten();
+    let analyzed = analyzed.as_ref().unwrap_or(src_image);
+    // A uniform image keeps its crop
+    let trimmed = options.trim.map(|tolerance| ResizeOptions {
+        crop: trim::bounds(analyzed, options.crop, tolerance).or(options.crop),
+        ..*options
+    });
+    let options = trimmed.as_ref().unwrap_or(options);
+    let mut plan = resize::plan(src_image.width(), src_image.height(), options)
+        .and_then(|plan| plan.check(&limits(config)).map(|()| plan))
+        .map_err(ProcessError::Invalid)?;
+    if let Some(crop) = plan.crop.filter(|_| smart_crop) {
+        if let Some(crop) = smart::position(analyzed, options.crop, crop) {
+            plan.crop = Some(crop);
+        }
+    }
+
+    let resized = resize::resample(
+        &mut resizer,
+        src_image,
+        plan.crop,
+        plan.width,
+        plan.height,
+        params.linear != Some(false),
+    );
+    let dst_image = resized.map_err(|err| {
+        tracing::error!(path, "Resize image error {err:#}");
+        ProcessError::Resize
+    })?;
+
+    // Only padding and orienting work on 16-bit pixels, the other stages get them rounded
+    let keep_16_bit =
+        params.depth == Some(BitDepth::Sixteen) && !effects.changes_pixels() && config.watermark.is_none();
+    let dst_image = match depth::to_8_bit(&dst_image) {
+        Some(rounded) if !keep_16_bit => rounded,
+        _ => dst_image,
+    };
+    let dst_image = effects.apply(dst_image);
+    // Padding adds transparency to the output when the background isn't opaque
+    let dst_image = match plan.canvas {
+        Some((width, height)) => resize::pad(&dst_image, width, height, params.bg.unwrap_or(Color([0; 4]))),
+        None => dst_image,
+    };
+
+    Ok(effects.orient(dst_image))
+}
+
+/// Encode `dst_image` in the requested `format`, with the quality used. Without one the format of the source
+/// `bytes` is kept when it can be encoded. The output cache `key` of the image remembers its automatic quality.
+fn encode_output(
+    path: &str,
+    bytes: &[u8],
+    dst_image: fir::Image<'static>,
+    params: &Params,
+    format: Option<OutputFormat>,
+    key: Option<&str>,
+    config: &Cli,
+) -> Result<(OutputFormat, u8, Vec<u8>), ProcessError> {
+    let has_alpha = depth::has_alpha(&dst_image);
+
+    // Without a requested or negotiated format keep the source one, and its transparency when it can't be encoded
+    let format = format
+        .or_else(|| {
+            image::guess_format(bytes)
+                .ok()
+                .and_then(OutputFormat::from_image_format)
+                .filter(|format| format.is_available())
+        })
+        .unwrap_or(if has_alpha {
+            OutputFormat::Png
+        } else {
+            OutputFormat::Jpeg
+        });
+    let dst_image = if has_alpha && !format.supports_alpha() {
+        // A translucent `bg` is itself composited onto the configured background
+        let background = params
+            .bg
+            .filter(|bg| bg.is_opaque())
+            .map_or(config.background, Color::rgb);
+        format::flatten_alpha(&dst_image, background)
+    } else {
+        dst_image
+    };
+
+    // Nothing but the pixels reaches the output unless the color profile and EXIF data are explicitly kept
+    let (icc_profile, exif) = match params.strip.unwrap_or(Strip::All) {
+        Strip::All => (None, None),
+        Strip::ExceptIcc => (metadata::icc_profile(bytes), None),
+        Strip::Nothing => (metadata::icc_profile(bytes), metadata::exif(bytes)),
+    };
+    let encode_options = EncodeOptions {
+        quality: output_quality(params, format, config),
+        png_compression: params.png_level.unwrap_or_default(),
+        png_filter: params.png_filter.unwrap_or_default(),
+        avif_speed: params.speed.unwrap_or(DEFAULT_AVIF_SPEED),
+        progressive: params.progressive == Some(true),
+        jpeg_encoder: config.jpeg_encoder,
+        subsampling: params.subsampling.unwrap_or_default(),
+        lossless: params.lossless == Some(true),
+    };
+    let encoded =
+        if params.quality() == Some(Quality::Auto) && auto_quality::supports(format) && !encode_options.lossless {
+            auto_quality::encode(
+                key,
+                path,
+                bytes,
+                format,
+                &dst_image,
+                config.auto_quality_target,
+                encode_options.quality,
+                |quality| {
+                    format::encode(
+                        format,
+                        &EncodeOptions {
+                            quality,
+                            ..encode_options
+                        },
+                        &dst_image,
+                    )
+                },
+            )
+        } else {
+            format::encode(format, &encode_options, &dst_image).map(|data| (encode_options.quality, data))
+        };
+    let (quality, result_buf) = encoded.map_err(|err| {
+        tracing::error!(path, "Encode image error {err:#}");
+        ProcessError::Encode
+    })?;
+    let result_buf = match icc_profile {
+        Some(profile) => metadata::embed_icc_profile(
+            format,
+            result_buf,
+            &profile,
+            dst_image.width().get(),
+            dst_image.height().get(),
+            depth::has_alpha(&dst_image),
+        ),
+        None => result_buf,
+    };
+    let result_buf = match exif {
+        Some(exif) => metadata::embed_exif(
+            format,
+            result_buf,
+            &exif,
+            dst_image.width().get(),
+            dst_image.height().get(),
+            depth::has_alpha(&dst_image),
+        ),
+        None => result_buf,
+    };
+
+    Ok((format, quality, result_buf))
+}
+
+/// Responses shared caches may keep, for as long as configured for their kind
+#[derive(Debug, Clone, Copy)]
+enum Cached {
+    /// Image, the origin `Cache-Control` can shorten its age or forbid caching
+    Success(Option<CachePolicy>),
+    Fallback,
+    NotFound,
+    /// Decoding it again would fail the same way
+    Unsupported,
+    /// The source may have been fetched partially
+    DecodeError,
+    ProcessError,
+}
+
+/// Seconds shared caches may keep each kind of response, from the `--cache-*` flags
+#[derive(Debug, Clone, Copy)]
+struct CacheAges {
+    success: u64,
+    fallback: u64,
+    not_found: u64,
+    unsupported: u64,
+    decode_error: u64,
+    process_error: u64,
+}
+
+impl CacheAges {
+    fn new(config: &Cli) -> Self {
+        Self {
+            success: config.max_cache_age,
+            fallback: config.cache_fallback,
+            not_found: config.cache_404,
+            unsupported: config.cache_unsupported,
+            decode_error: config.cache_decode_error,
+            process_error: config.cache_process_error,
+        }
+    }
+
+    /// `Cache-Control` of a response
+    fn header(self, cached: Cached) -> HeaderValue {
+        let age = match cached {
+            Cached::Success(Some(CachePolicy::NoStore)) => return HeaderValue::from_static("no-store"),
+            Cached::Success(Some(CachePolicy::MaxAge(age))) => age.min(self.success),
+            Cached::Success(None) => self.success,
+            Cached::Fallback => self.fallback,
+            Cached::NotFound => self.not_found,
+            Cached::Unsupported => self.unsupported,
+            Cached::DecodeError => self.decode_error,
+            Cached::ProcessError => self.process_error,
+        };
+
+        HeaderValue::from_str(&format!("public, s-max-age={age}")).unwrap()
+    }
+}
+
+/// `Server-Timing` of the stages, in milliseconds
+fn server_timing(stages: &[(&str, Duration)]) -> HeaderValue {
+    let timing = stages
+        .iter()
+        .map(|(name, duration)| format!("{name};dur={:.1}", duration.as_secs_f64() * 1000.0))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    HeaderValue::from_str(&timing).unwrap()
+}
+
+/// `attachment` disposition named after the source file, with the extension of the output format
+fn content_disposition(path: &str, format: OutputFormat) -> HeaderValue {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let stem = match name.rsplit_once('.') {
+        Some((stem, _)) if !stem.is_empty() => stem,
+        _ if !name.is_empty() => name,
+        _ => "image",
+    };
+    let filename = format!("{stem}.{}", format.extension());
+
+    // Plain ASCII `filename` for old clients, the others read the exact name from `filename*`
+    let ascii = filename
+        .chars()
+        .map(|c| {
+            if c == ' ' || (c.is_ascii_graphic() && c != '"' && c != '\\') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let encoded = percent_encoding::utf8_percent_encode(&filename, percent_encoding::NON_ALPHANUMERIC);
+    HeaderValue::from_str(&format!("attachment; filename=\"{ascii}\"; filename*=UTF-8''{encoded}")).unwrap()
+}
+
+/// Respond with the image and its `ETag`, or with `304 Not Modified` when the client's `If-None-Match` matches it
+fn image_response(mut headers: HeaderMap, data: Bytes, request_headers: &HeaderMap) -> Response {
+    let etag = format!("\"{}\"", hex::encode(&Sha256::digest(&data)[..16]));
+    headers.insert(header::ETAG, HeaderValue::from_str(&etag).unwrap());
+
+    // `If-None-Match` uses the weak comparison, a `W/` prefix doesn't prevent a match
+    let not_modified = request_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag);
+    if not_modified {
+        return (StatusCode::NOT_MODIFIED, headers).into_response();
+    }
+
+    // Ranges are only served from the output the client already has parts of, `If-Range` dates never match
+    headers.insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    let if_range = request_headers
+        .get(header::IF_RANGE)
+        .is_none_or(|value| value.to_str().is_ok_and(|value| value == etag));
+    let range = request_headers
+        .get(header::RANGE)
+        .and_then(|value| value.to_str().ok())
+        .filter(|_| if_range)
+        .and_then(|value| byte_range(value, data.len()));
+    let (status, data) = match range {
+        Some(Ok(range)) => {
+            let content_range = format!("bytes {}-{}/{}", range.start, range.end - 1, data.len());
+            headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&content_range).unwrap());
+            (StatusCode::PARTIAL_CONTENT, data.slice(range))
+        }
+        Some(Err(())) => {
+            let content_range = format!("bytes */{}", data.len());
+            headers.insert(header::CONTENT_RANGE, HeaderValue::from_str(&content_range).unwrap());
+            return (StatusCode::RANGE_NOT_SATISFIABLE, headers).into_response();
+        }
+        None => (StatusCode::OK, data),
+    };
+
+    // The whole output is already in memory, its length doesn't depend on hyper reading the body size hint
+    headers.insert(header::CONTENT_LENGTH, HeaderValue::from(data.len()));
+    (status, headers, body::Full::new(data)).into_response()
+}
+
+/// Bytes of a `len` bytes output requested by a `Range` header, `Err` when none of them exist. `None` for anything
+/// but a single `bytes` range, which is answered with the whole output.
+fn byte_range(value: &str, len: usize) -> Option<Result<std::ops::Range<usize>, ()>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+    if start.is_empty() {
+        // The last `suffix` bytes
+        let suffix = end.parse::<usize>().ok()?;
+        if suffix == 0 || len == 0 {
+            return Some(Err(()));
+        }
+        return Some(Ok(len.saturating_sub(suffix)..len));
+    }
+
+    let start = start.parse::<usize>().ok()?;
+    let end = match end {
+        "" => None,
+        end => Some(end.parse::<usize>().ok()?),
+    };
+    if end.is_some_and(|end| end < start) {
+        return None;
+    }
+    if start >= len {
+        return Some(Err(()));
+    }
+
+    Some(Ok(start..end.map_or(len, |end| end.min(len - 1) + 1)))
+}
+
+/// Media type of a non-image source, declared by its origin or else guessed from the extension of its `path`, when
+/// it is one of the `allowed` types or `type/*` ranges
+fn non_image_content_type(path: &str, declared: Option<&HeaderValue>, allowed: &[String]) -> Option<HeaderValue> {
+    let content_type = match declared {
+        Some(declared) => declared.clone(),
+        None => HeaderValue::from_static(mime_guess::from_path(path).first_raw()?),
+    };
+    let essence = content_type
+        .to_str()
+        .ok()?
+        .split(';')
+        .next()?
+        .trim()
+        .to_ascii_lowercase();
+    let allowed = allowed.iter().any(|allowed| {
+        let allowed = allowed.trim().to_ascii_lowercase();
+        match allowed.strip_suffix('*') {
+            Some(prefix) if prefix.ends_with('/') => essence.starts_with(prefix),
+            _ => essence == allowed,
+        }
+    });
+
+    allowed.then_some(content_type)
+}
+
+fn image_headers(format: OutputFormat, negotiated: bool, hinted_dpr: bool, cache_control: HeaderValue) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(format.content_type()));
+    headers.insert(header::CACHE_CONTROL, cache_control);
+    // Asks browsers to send the device pixel ratio with their next requests
+    headers.insert("accept-ch", HeaderValue::from_static("Sec-CH-DPR, DPR"));
+    let vary = match (negotiated, hinted_dpr) {
+        (true, true) => Some("Accept, Sec-CH-DPR, DPR"),
+        (true, false) => Some("Accept"),
+        (false, true) => Some("Sec-CH-DPR, DPR"),
+        (false, false) => None,
+    };
+    if let Some(vary) = vary {
+        headers.insert(header::VARY, HeaderValue::from_static(vary));
+    }
+
+    headers
+}
+
+/// Device pixel ratio of the `Sec-CH-DPR` client hint, or of the legacy `DPR` one
+fn client_hint_dpr(headers: &HeaderMap) -> Option<f32> {
+    ["sec-ch-dpr", "dpr"].iter().find_map(|name| {
+        headers
+            .get(*name)?
+            .to_str()
+            .ok()?
+            .trim()
+            .parse::<f32>()
+            .ok()
+            .filter(|dpr| dpr.is_finite())
+    })
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Params {
+    width: Option<NonZeroU32>,
+    height: Option<NonZeroU32>,
+    w: Option<NonZeroU32>,
+    h: Option<NonZeroU32>,
+    format: Option<OutputFormat>,
+    f: Option<OutputFormat>,
+    /// imgix's name of `format`
+    fm: Option<OutputFormat>,
+    /// 1-100, or `auto`
+    quality: Option<Quality>,
+    q: Option<Quality>,
+    fit: Option<FitMode>,
+    gravity: Option<Gravity>,
+    /// `x,y` fractions (0-1) of the source kept in sight by `fit=cover`, taking precedence over `gravity`
+    focal: Option<FocalPoint>,
+    crop: Option<CropRect>,
+    filter: Option<ResizeFilter>,
+    dpr: Option<f32>,
+    png_level: Option<PngCompression>,
+    png_filter: Option<PngFilter>,
+    /// AVIF encoder speed
+    speed: Option<u8>,
+    /// Metadata of the source kept in the output, all of it is dropped by default
+    strip: Option<Strip>,
+    /// Answer with the BlurHash of the source instead of the image
+    blurhash: Option<bool>,
+    components_x: Option<u32>,
+    components_y: Option<u32>,
+    /// Answer with the dominant or average color of the source instead of the image
+    color: Option<ColorMode>,
+    /// Fills the padding of `fit=contain` and `fit=pad` (transparent otherwise) and replaces transparency for formats without alpha
+    bg: Option<Color>,
+    /// Unsharp mask amount applied after resizing, clamped to 0-3
+    sharpen: Option<f32>,
+    /// Gaussian blur sigma applied after resizing
+    blur: Option<f32>,
+    /// Resize averaging linear light (the default), `false` averages the sRGB values as they are: faster, but darkens
+    /// contrasted details
+    linear: Option<bool>,
+    /// Color transform applied after resizing
+    effect: Option<ColorEffect>,
+    /// Clockwise rotation (90, 180 or 270) applied to the output
+    rotate: Option<Rotation>,
+    /// Mirroring (`h` or `v`) applied to the output, after `rotate`
+    flip: Option<Flip>,
+    /// Progressive JPEG instead of baseline
+    progressive: Option<bool>,
+    /// JPEG chroma subsampling (`444`, `422` or `420`)
+    subsampling: Option<ChromaSubsampling>,
+    /// Lossless WebP, `quality` becoming the compression effort. Only with `format=webp`
+    lossless: Option<bool>,
+    /// Bits per channel (`8` or `16`) of the output, 16-bit sources keeping theirs with `16`. Only with `format=png`
+    depth: Option<BitDepth>,
+    /// Answer with the format, dimensions and byte size of the source instead of the image
+    info: Option<bool>,
+    /// Answer with a JSON manifest of the URLs of these widths instead of the image
+    srcset: Option<srcset::Widths>,
+    /// Corner or side of the output the `--watermark` is stamped on
+    watermark_gravity: Option<Gravity>,
+    /// Opacity of the `--watermark`, from 0 to 1
+    watermark_opacity: Option<f32>,
+    /// Whether outputs may be larger than the source, `--no-upscale` when absent
+    upscale: Option<bool>,
+    /// Cut away the border of uniform color around the source (or its `crop`) before resizing
+    trim: Option<bool>,
+    /// Difference (in percent, 0-100) from the border color still counted as border by `trim`
+    trim_tolerance: Option<f32>,
+    /// Answer with `Content-Disposition: attachment`, named after the source with the output extension
+    download: Option<bool>,
+    /// Page of PDF sources to render, from 1
+    #[cfg(feature = "pdf")]
+    page: Option<std::num::NonZeroU16>,
+}
+
+impl Params {
+    /// `format`, or its `f` and `fm` short names
+    fn format(&self) -> Option<OutputFormat> {
+        self.format.or(self.f).or(self.fm)
+    }
+
+    /// `quality`, or its `q` short name
+    fn quality(&self) -> Option<Quality> {
+        self.quality.or(self.q)
+    }
+
+    /// Parameters with the short names given under the full ones, and without the ones only changing the response
+    /// headers, the same output having the same parameters
+    fn normalized(&self) -> Params {
+        Params {
+            // `Content-Disposition` is set from the request, cached or not
+            download: None,
+            width: self.width.or(self.w),
+            height: self.height.or(self.h),
+            w: None,
+            h: None,
+            format: self.format(),
+            f: None,
+            fm: None,
+            quality: self.quality(),
+            q: None,
+            ..self.clone()
+        }
+    }
+}
+
+fn parse_bind(value: &str) -> Result<Bind, String> {
+    // A bare IPv6 address may be bracketed as it is with a port
+    let ip = value
+        .strip_prefix('[')
+        .and_then(|ip| ip.strip_suffix(']'))
+        .unwrap_or(value);
+    if let Ok(ip) = ip.parse() {
+        return Ok(Bind { ip, port: None });
+    }
+
+    value
+        .parse::<SocketAddr>()
+        .map(|addr| Bind {
+            ip: addr.ip(),
+            port: Some(addr.port()),
+        })
+        .map_err(|_| format!("`{value}` is neither an IP address nor an `ip:port`"))
+}
+
+fn parse_origin_header(value: &str) -> Result<(HeaderName, HeaderValue), String> {
+    let (name, header_value) = value
+        .split_once(':')
+        .ok_or_else(|| format!("`{value}` isn't a `Name: Value` header"))?;
+    let name = name
+        .trim()
+        .parse::<HeaderName>()
+        .map_err(|err| format!("`{name}`: {err}"))?;
+    let header_value = HeaderValue::from_str(header_value.trim()).map_err(|err| format!("`{value}`: {err}"))?;
+
+    Ok((name, header_value))
+}
+
+impl<'de> Deserialize<'de> for Bind {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        parse_bind(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// `origin_header` of the `--config` file, an array of `Name: Value` headers
+fn deserialize_origin_headers<'de, D>(deserializer: D) -> Result<Option<Vec<(HeaderName, HeaderValue)>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|header| parse_origin_header(header))
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+/// `forward_headers` of the `--config` file, an array of header names
+fn deserialize_header_names<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<HeaderName>>, D::Error> {
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|name| name.parse().map_err(|err| format!("`{name}`: {err}")))
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(de::Error::custom)
+}
+
+fn deserialize_background<'de, D: Deserializer<'de>>(deserializer: D) -> Result<[u8; 3], D::Error> {
+    parse_hex_color(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+}
+
+fn parse_hex_color(value: &str) -> Result<[u8; 3], String> {
+    let hex = value.strip_prefix('#').unwrap_or(value);
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(format!("expected a `rrggbb` hex color, got `{value}`"));
+    }
+
+    let mut color = [0; 3];
+    for (i, channel) in color.iter_mut().enumerate() {
+        *channel = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|err| format!("`{value}`: {err}"))?;
+    }
+
+    Ok(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use image::{codecs::jpeg::JpegEncoder, RgbImage};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    /// Folder holding a 64x32 `a.png`, removed by the test once done
+    fn images(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("image-resize-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        RgbImage::from_fn(64, 32, |x, y| image::Rgb([x as u8 * 4, y as u8 * 8, 128]))
+            .save(folder.join("a.png"))
+            .unwrap();
+        folder
+    }
+
+    /// The image routes serving the `folder`, as `run` builds them from the `args`
+    fn app(folder: &Path, args: &[&str]) -> Router {
+        cached_app(folder, args).0
+    }
+
+    /// `app`, along with its cache of outputs
+    fn cached_app(folder: &Path, args: &[&str]) -> (Router, Arc<Cache>) {
+        let folder = folder.to_str().unwrap();
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--local-folder", folder].iter().chain(args));
+        let sources = Arc::new(SourceChain::new(vec![Box::new(LocalSource::new(folder, None))]));
+        let memory_cache = cli.memory_cache_mb.map(|mb| MemoryCache::new(mb * 1024 * 1024));
+        let cache = Arc::new(Cache::new(memory_cache, None, cache::config_fingerprint(&cli)));
+        let app = Router::new()
+            .fallback(get(handler))
+            .layer(Extension(sources))
+            .layer(Extension(cache.clone()))
+            .layer(Extension(None::<Arc<Semaphore>>))
+            .layer(Extension(cli));
+        (app, cache)
+    }
+
+    async fn get_image(app: Router, uri: &str, headers: &[(HeaderName, &str)]) -> (StatusCode, HeaderMap, Bytes) {
+        let mut request = Request::get(uri);
+        for (name, value) in headers {
+            request = request.header(name, *value);
+        }
+        let resp = app.oneshot(request.body(body::Body::empty()).unwrap()).await.unwrap();
+        let (parts, body) = resp.into_parts();
+        (parts.status, parts.headers, hyper::body::to_bytes(body).await.unwrap())
+    }
+
+    #[tokio::test]
+    async fn content_length_of_output() {
+        let folder = images("content-length");
+        let (status, headers, data) = get_image(app(&folder, &[]), "/a.png?width=16&format=jpeg", &[]).await;
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/jpeg");
+        assert_eq!(headers[header::CONTENT_LENGTH], data.len().to_string().as_str());
+    }
+
+    #[tokio::test]
+    async fn passthrough_without_parameters() {
+        let folder = images("passthrough");
+        let source = std::fs::read(folder.join("a.png")).unwrap();
+        // Even when the browser would negotiate another format
+        let accept = [(header::ACCEPT, "image/avif,image/webp,*/*")];
+        let (status, headers, data) = get_image(app(&folder, &[]), "/a.png", &accept).await;
+        let (_, _, resized) = get_image(app(&folder, &[]), "/a.png?width=64", &accept).await;
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        assert_eq!(data, source);
+        assert_ne!(resized, source);
+    }
+
+    #[tokio::test]
+    async fn traversal_not_found() {
+        let folder = images("traversal");
+        let app = app(&folder, &[]);
+        let mut statuses = Vec::new();
+        for uri in [
+            "/../../etc/passwd",
+            "/%2e%2e/%2e%2e/etc/passwd",
+            "/..%2F..%2Fetc%2Fpasswd?width=10",
+        ] {
+            statuses.push(get_image(app.clone(), uri, &[]).await.0);
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(statuses, [StatusCode::NOT_FOUND; 3]);
+    }
+
+    /// Mean absolute difference of the channels of two images of the same size and pixel type
+    fn difference(a: &fir::Image, b: &fir::Image) -> f64 {
+        let total = a
+            .buffer()
+            .iter()
+            .zip(b.buffer())
+            .map(|(a, b)| a.abs_diff(*b) as u64)
+            .sum::<u64>();
+        total as f64 / a.buffer().len() as f64
+    }
+
+    #[tokio::test]
+    async fn jpeg_downscaled_decode() {
+        let photo = RgbImage::from_fn(512, 384, |x, y| {
+            let (x, y) = (x as f32, y as f32);
+            let wave = |period: f32, phase: f32| (127.5 + 127.5 * ((x + 2.0 * y) / period + phase).sin()) as u8;
+            image::Rgb([wave(23.0, 0.0), wave(41.0, 1.0), wave(67.0, 2.0)])
+        });
+        let mut bytes = Vec::new();
+        JpegEncoder::new_with_quality(&mut bytes, 90)
+            .encode_image(&photo)
+            .unwrap();
+        let limits = limits(&Cli::default());
+
+        for (width, scaled) in [(64, Some((64, 48))), (100, Some((128, 96))), (300, None)] {
+            // The decode waits on the runtime from the blocking thread pool
+            let decoded = tokio::task::spawn_blocking({
+                let bytes = bytes.clone();
+                move || {
+                    let mut options = ResizeOptions {
+                        width: NonZeroU32::new(width),
+                        ..Default::default()
+                    };
+                    decode_jpeg_downscaled("/a.jpg", &bytes, &mut options, &limits, Duration::from_secs(10))
+                }
+            })
+            .await
+            .unwrap();
+            let Ok(decoded) = decoded else {
+                panic!("width {width}: not decoded");
+            };
+            let Some(decoded) = decoded else {
+                assert_eq!(scaled, None, "width {width}");
+                continue;
+            };
+            assert_eq!(Some((decoded.image.width(), decoded.image.height())), scaled);
+            assert_eq!(decoded.original, (512, 384));
+
+            // Against the output of the whole source, resized the same way
+            let (width, height) = (NonZeroU32::new(width).unwrap(), NonZeroU32::new(width * 3 / 4).unwrap());
+            let resized = |image: &DynamicImage| {
+                let mut resizer = fir::Resizer::new(ResizeFilter::Lanczos3.algorithm());
+                resize::resample(&mut resizer, &to_fir_image(image), None, width, height, true).unwrap()
+            };
+            let full = resized(&image::load_from_memory(&bytes).unwrap());
+            let downscaled = resized(&decoded.image);
+            let difference = difference(&full, &downscaled);
+            assert!(difference < 3.0, "width {width}: {difference}");
+        }
+    }
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(byte_range("bytes=0-99", 1000), Some(Ok(0..100)));
+        assert_eq!(byte_range("bytes=900-", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=990-2000", 1000), Some(Ok(990..1000)));
+        assert_eq!(byte_range("bytes=-100", 1000), Some(Ok(900..1000)));
+        assert_eq!(byte_range("bytes=-2000", 1000), Some(Ok(0..1000)));
+        // Not satisfiable
+        assert_eq!(byte_range("bytes=1000-", 1000), Some(Err(())));
+        assert_eq!(byte_range("bytes=-0", 1000), Some(Err(())));
+        // Answered with the whole output
+        for value in ["bytes=0-1,5-9", "bytes=9-5", "items=0-9", "bytes=a-b", "bytes=5"] {
+            assert_eq!(byte_range(value, 1000), None, "{value}");
+        }
+    }
+
+    #[tokio::test]
+    async fn range_responses() {
+        let folder = images("range");
+        let app = app(&folder, &[]);
+        let uri = "/a.png?width=32";
+        let (_, _, whole) = get_image(app.clone(), uri, &[]).await;
+        let (partial, partial_headers, data) = get_image(app.clone(), uri, &[(header::RANGE, "bytes=10-19")]).await;
+        let (unsatisfiable, headers, _) = get_image(app, uri, &[(header::RANGE, "bytes=100000-")]).await;
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(partial, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(data, whole.slice(10..20));
+        let content_range = format!("bytes 10-19/{}", whole.len());
+        assert_eq!(partial_headers[header::CONTENT_RANGE], content_range.as_str());
+        assert_eq!(partial_headers[header::CONTENT_LENGTH], "10");
+        assert_eq!(unsatisfiable, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(
+            headers[header::CONTENT_RANGE],
+            format!("bytes */{}", whole.len()).as_str()
+        );
+    }
+
+    /// Run with `cargo test --no-default-features`
+    #[cfg(not(feature = "avif"))]
+    #[tokio::test]
+    async fn avif_accepted_without_encoder() {
+        let folder = images("no-avif");
+        let app = app(&folder, &[]);
+        let (status, headers, _) = get_image(app.clone(), "/a.png?width=16", &[(header::ACCEPT, "image/avif")]).await;
+        let (fallback, fallback_headers, _) =
+            get_image(app, "/a.png?width=16", &[(header::ACCEPT, "image/avif,image/webp")]).await;
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(headers[header::CONTENT_TYPE], "image/png");
+        assert_eq!(fallback, StatusCode::OK);
+        assert_eq!(fallback_headers[header::CONTENT_TYPE], "image/webp");
+    }
+
+    #[test]
+    fn every_flag_problem() {
+        let cli = Cli::parse_from([
+            env!("CARGO_PKG_NAME"),
+            "--check",
+            "--remote-cdn",
+            "https://cdn.example.com",
+            "--unix-socket",
+            "/tmp/image-resize.sock",
+            "--port",
+            "4000",
+            "--default-scale",
+            "2",
+        ]);
+        let problems = flag_problems(&cli);
+        assert_eq!(problems.len(), 3, "{problems:?}");
+        assert!(problems[0].contains("'check_path'"));
+
+        let cli = Cli::parse_from([env!("CARGO_PKG_NAME"), "--local-folder", "/srv/images"]);
+        assert_eq!(flag_problems(&cli), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn signature_required() {
+        let folder = images("signature");
+        let app = app(&folder, &["--signing-secret", "secret"]);
+        let sig = signature::sign(b"secret", "/a.png", "width=16");
+        let mut statuses = Vec::new();
+        for uri in [
+            format!("/a.png?width=16&sig={sig}"),
+            "/a.png?width=16".to_owned(),
+            "/a.png".to_owned(),
+            format!("/a.png?width=32&sig={sig}"),
+            format!("/a.png?width=16&sig={}", "0".repeat(64)),
+        ] {
+            statuses.push(get_image(app.clone(), &uri, &[]).await.0);
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(statuses[0], StatusCode::OK);
+        assert_eq!(statuses[1..], [StatusCode::FORBIDDEN; 4]);
+    }
+
+    #[tokio::test]
+    async fn download_shares_cached_output() {
+        let folder = images("download");
+        let (app, cache) = cached_app(&folder, &["--memory-cache-mb", "1"]);
+        let (_, headers, data) = get_image(app.clone(), "/a.png?width=16", &[]).await;
+        // Stored once the response is sent
+        while cache.stats().await.memory.is_some_and(|memory| memory.entries == 0) {
+            tokio::task::yield_now().await;
+        }
+        let (_, download_headers, download) = get_image(app.clone(), "/a.png?width=16&download=true", &[]).await;
+        let (_, again_headers, _) = get_image(app, "/a.png?width=16", &[]).await;
+        let stats = cache.stats().await;
+        std::fs::remove_dir_all(folder).unwrap();
+
+        assert_eq!(download, data);
+        assert_eq!((stats.hits, stats.memory.unwrap().entries), (2, 1));
+        assert!(headers.get(header::CONTENT_DISPOSITION).is_none());
+        let disposition = download_headers[header::CONTENT_DISPOSITION].to_str().unwrap();
+        assert!(
+            disposition.starts_with("attachment; filename=\"a.png\""),
+            "{disposition}"
+        );
+        assert!(again_headers.get(header::CONTENT_DISPOSITION).is_none());
+    }
 }
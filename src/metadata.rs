@@ -0,0 +1,371 @@
+use std::io::Cursor;
+
+use image::{
+    codecs::{jpeg::JpegDecoder, png::PngDecoder, webp::WebPDecoder},
+    DynamicImage, ImageDecoder, ImageFormat,
+};
+
+use serde::Deserialize;
+
+use crate::format::OutputFormat;
+
+/// Read the EXIF orientation (1 to 8) of an encoded image, if it has one
+pub fn exif_orientation(bytes: &[u8]) -> Option<u32> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)).ok()?;
+    exif.get_field(exif::Tag::Orientation, exif::In::PRIMARY)?
+        .value
+        .get_uint(0)
+}
+
+/// Metadata of the source kept in the output, by the `strip` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum Strip {
+    /// `true`, the default: nothing but the pixels
+    #[serde(rename = "true")]
+    All,
+    /// `icc` or `false`: the ICC color profile
+    #[serde(rename = "icc", alias = "false")]
+    ExceptIcc,
+    /// `none`: the ICC color profile and the EXIF data, less what `exif` leaves out
+    #[serde(rename = "none")]
+    Nothing,
+}
+
+/// Read the EXIF data of an encoded image, written again as a TIFF structure for the output.
+///
+/// The orientation is reset to 1 as the decoded pixels already have it applied and viewers would otherwise turn them
+/// a second time. What locates the source or identifies its camera and owner (GPS, serial numbers, unique ID, maker
+/// notes) is left out, as are the thumbnail and pixel dimensions, which show the source rather than the output.
+pub fn exif(bytes: &[u8]) -> Option<Vec<u8>> {
+    let exif = exif::Reader::new().read_from_container(&mut Cursor::new(bytes)).ok()?;
+    let upright = exif::Field {
+        tag: exif::Tag::Orientation,
+        ifd_num: exif::In::PRIMARY,
+        value: exif::Value::Short(vec![1]),
+    };
+
+    let mut writer = exif::experimental::Writer::new();
+    for field in exif.fields().filter(|field| is_kept(field)) {
+        writer.push_field(if field.tag == exif::Tag::Orientation {
+            &upright
+        } else {
+            field
+        });
+    }
+    let mut tiff = Cursor::new(Vec::new());
+    writer.write(&mut tiff, exif.little_endian()).ok()?;
+    Some(tiff.into_inner())
+}
+
+fn is_kept(field: &exif::Field) -> bool {
+    const LEFT_OUT: [exif::Tag; 7] = [
+        exif::Tag::MakerNote,
+        exif::Tag::BodySerialNumber,
+        exif::Tag::LensSerialNumber,
+        exif::Tag::CameraOwnerName,
+        exif::Tag::ImageUniqueID,
+        exif::Tag::PixelXDimension,
+        exif::Tag::PixelYDimension,
+    ];
+
+    field.ifd_num == exif::In::PRIMARY
+        && field.tag.context() != exif::Context::Gps
+        && !LEFT_OUT.contains(&field.tag)
+        // Values of unknown types can't be written again
+        && !matches!(field.value, exif::Value::Unknown(..))
+}
+
+/// Rotate and flip a decoded image so it displays upright for the given EXIF orientation
+pub fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        // Transpose
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        // Transverse
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+/// Read the ICC color profile embedded in an encoded JPEG, PNG or WebP image, if it has one
+pub fn icc_profile(bytes: &[u8]) -> Option<Vec<u8>> {
+    let cursor = Cursor::new(bytes);
+    match image::guess_format(bytes).ok()? {
+        ImageFormat::Jpeg => JpegDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::Png => PngDecoder::new(cursor).ok()?.icc_profile(),
+        ImageFormat::WebP => WebPDecoder::new(cursor).ok()?.icc_profile(),
+        _ => None,
+    }
+}
+
+/// Embed an ICC color profile into an encoded JPEG or WebP image, other formats are returned untouched
+pub fn embed_icc_profile(
+    format: OutputFormat,
+    data: Vec<u8>,
+    profile: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+) -> Vec<u8> {
+    match format {
+        OutputFormat::Jpeg => embed_jpeg_icc_profile(data, profile),
+        OutputFormat::Webp => embed_webp_icc_profile(data, profile, width, height, has_alpha),
+        OutputFormat::Png | OutputFormat::Avif | OutputFormat::Gif => data,
+    }
+}
+
+/// Embed EXIF data into an encoded JPEG or WebP image, other formats are returned untouched
+pub fn embed_exif(
+    format: OutputFormat,
+    data: Vec<u8>,
+    exif: &[u8],
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+) -> Vec<u8> {
+    match format {
+        OutputFormat::Jpeg => embed_jpeg_exif(data, exif),
+        OutputFormat::Webp => embed_webp_exif(data, exif, width, height, has_alpha),
+        OutputFormat::Png | OutputFormat::Avif | OutputFormat::Gif => data,
+    }
+}
+
+/// Insert the data as an `APP1` segment, after the `JFIF` header when there is one
+fn embed_jpeg_exif(data: Vec<u8>, exif: &[u8]) -> Vec<u8> {
+    const SIGNATURE: &[u8] = b"Exif\0\0";
+
+    let length = 2 + SIGNATURE.len() + exif.len();
+    if !data.starts_with(&[0xFF, 0xD8]) || length > u16::MAX as usize {
+        return data;
+    }
+
+    let mut offset = 2;
+    if data[2..].starts_with(&[0xFF, 0xE0]) && data.len() >= 6 {
+        offset += 2 + u16::from_be_bytes([data[4], data[5]]) as usize;
+    }
+
+    let mut output = Vec::with_capacity(data.len() + 2 + length);
+    output.extend_from_slice(&data[..offset]);
+    output.extend_from_slice(&[0xFF, 0xE1]);
+    output.extend_from_slice(&(length as u16).to_be_bytes());
+    output.extend_from_slice(SIGNATURE);
+    output.extend_from_slice(exif);
+    output.extend_from_slice(&data[offset..]);
+
+    output
+}
+
+/// Append an `EXIF` chunk, turning a simple WebP into the extended format if needed
+fn embed_webp_exif(data: Vec<u8>, exif: &[u8], width: u32, height: u32, has_alpha: bool) -> Vec<u8> {
+    const EXIF_FLAG: u8 = 0x08;
+
+    if data.len() < 20 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return data;
+    }
+
+    let mut output = Vec::with_capacity(data.len() + exif.len() + 40);
+    let rest = extended_webp_header(&mut output, &data, EXIF_FLAG, width, height, has_alpha);
+    output.extend_from_slice(&data[rest..]);
+    push_riff_chunk(&mut output, b"EXIF", exif);
+
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    output
+}
+
+/// Insert the profile as `APP2` segments, after the `JFIF` header when there is one
+fn embed_jpeg_icc_profile(data: Vec<u8>, profile: &[u8]) -> Vec<u8> {
+    const SIGNATURE: &[u8] = b"ICC_PROFILE\0";
+    // Segment length (2 bytes), signature, sequence number and count
+    const MAX_CHUNK: usize = u16::MAX as usize - 2 - SIGNATURE.len() - 2;
+
+    let chunks = profile.chunks(MAX_CHUNK).collect::<Vec<_>>();
+    if !data.starts_with(&[0xFF, 0xD8]) || chunks.is_empty() || chunks.len() > u8::MAX as usize {
+        return data;
+    }
+
+    let mut offset = 2;
+    if data[2..].starts_with(&[0xFF, 0xE0]) && data.len() >= 6 {
+        offset += 2 + u16::from_be_bytes([data[4], data[5]]) as usize;
+    }
+
+    let mut output = Vec::with_capacity(data.len() + profile.len() + chunks.len() * (4 + SIGNATURE.len() + 2));
+    output.extend_from_slice(&data[..offset]);
+    for (i, chunk) in chunks.iter().enumerate() {
+        output.extend_from_slice(&[0xFF, 0xE2]);
+        output.extend_from_slice(&((2 + SIGNATURE.len() + 2 + chunk.len()) as u16).to_be_bytes());
+        output.extend_from_slice(SIGNATURE);
+        output.extend_from_slice(&[i as u8 + 1, chunks.len() as u8]);
+        output.extend_from_slice(chunk);
+    }
+    output.extend_from_slice(&data[offset..]);
+
+    output
+}
+
+/// Insert an `ICCP` chunk after the `VP8X` header, turning a simple WebP into the extended format if needed
+fn embed_webp_icc_profile(data: Vec<u8>, profile: &[u8], width: u32, height: u32, has_alpha: bool) -> Vec<u8> {
+    const ICC_FLAG: u8 = 0x20;
+
+    if data.len() < 20 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return data;
+    }
+
+    let mut output = Vec::with_capacity(data.len() + profile.len() + 40);
+    let rest = extended_webp_header(&mut output, &data, ICC_FLAG, width, height, has_alpha);
+    push_riff_chunk(&mut output, b"ICCP", profile);
+    output.extend_from_slice(&data[rest..]);
+
+    let riff_size = (output.len() - 8) as u32;
+    output[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    output
+}
+
+/// Write the RIFF header and a `VP8X` header with `flag` set to `output`, answering where the rest of `data` starts
+fn extended_webp_header(
+    output: &mut Vec<u8>,
+    data: &[u8],
+    flag: u8,
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+) -> usize {
+    const ALPHA_FLAG: u8 = 0x10;
+
+    output.extend_from_slice(&data[..12]);
+    if &data[12..16] == b"VP8X" {
+        let mut header = data[12..30].to_vec();
+        header[8] |= flag;
+        output.extend_from_slice(&header);
+        30
+    } else {
+        let mut header = [0; 10];
+        header[0] = flag | if has_alpha { ALPHA_FLAG } else { 0 };
+        header[4..7].copy_from_slice(&(width - 1).to_le_bytes()[..3]);
+        header[7..10].copy_from_slice(&(height - 1).to_le_bytes()[..3]);
+        push_riff_chunk(output, b"VP8X", &header);
+        12
+    }
+}
+
+fn push_riff_chunk(output: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    output.extend_from_slice(fourcc);
+    output.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    output.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        output.push(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use exif::{experimental::Writer, Field, In, Rational, Tag, Value};
+    use image::{codecs::jpeg::JpegEncoder, GrayImage, RgbImage};
+
+    use super::*;
+
+    /// JPEG of 4x2 pixels with the given EXIF orientation, along with private fields and a thumbnail
+    fn oriented_jpeg(orientation: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        JpegEncoder::new(&mut data).encode_image(&RgbImage::new(4, 2)).unwrap();
+
+        let field = |tag, value| Field {
+            tag,
+            ifd_num: In::PRIMARY,
+            value,
+        };
+        let fields = [
+            field(Tag::Orientation, Value::Short(vec![orientation])),
+            field(Tag::Make, Value::Ascii(vec![b"Camera".to_vec()])),
+            field(Tag::BodySerialNumber, Value::Ascii(vec![b"123456".to_vec()])),
+            field(Tag::PixelXDimension, Value::Long(vec![4])),
+            field(Tag::GPSLatitudeRef, Value::Ascii(vec![b"N".to_vec()])),
+            field(Tag::GPSLatitude, Value::Rational(vec![Rational::from((48, 1)); 3])),
+        ];
+        let mut writer = Writer::new();
+        for field in &fields {
+            writer.push_field(field);
+        }
+        writer.set_jpeg(&data, In::THUMBNAIL);
+        let mut tiff = Cursor::new(Vec::new());
+        writer.write(&mut tiff, false).unwrap();
+
+        embed_exif(OutputFormat::Jpeg, data.clone(), &tiff.into_inner(), 4, 2, false)
+    }
+
+    #[test]
+    fn exif_upright() {
+        for orientation in 1..=8 {
+            let source = oriented_jpeg(orientation);
+            assert_eq!(exif_orientation(&source), Some(orientation as u32));
+            let upright = apply_orientation(image::load_from_memory(&source).unwrap(), orientation as u32);
+            let expected = if orientation <= 4 { (4, 2) } else { (2, 4) };
+            assert_eq!(
+                (upright.width(), upright.height()),
+                expected,
+                "orientation {orientation}"
+            );
+
+            // The output carries the upright pixels, viewers mustn't turn them again
+            let mut output = Vec::new();
+            JpegEncoder::new(&mut output).encode_image(&upright).unwrap();
+            let output = embed_exif(OutputFormat::Jpeg, output, &exif(&source).unwrap(), 4, 2, false);
+            assert_eq!(exif_orientation(&output), Some(1), "orientation {orientation}");
+        }
+    }
+
+    #[test]
+    fn exif_left_out_fields() {
+        let source = oriented_jpeg(6);
+        let source_exif = exif::Reader::new()
+            .read_from_container(&mut Cursor::new(&source))
+            .unwrap();
+        assert!(source_exif.get_field(Tag::GPSLatitude, In::PRIMARY).is_some());
+        assert!(source_exif.fields().any(|field| field.ifd_num == In::THUMBNAIL));
+
+        let exif = exif::Reader::new().read_raw(exif(&source).unwrap()).unwrap();
+
+        assert!(exif.get_field(Tag::Make, In::PRIMARY).is_some());
+        for tag in [
+            Tag::BodySerialNumber,
+            Tag::PixelXDimension,
+            Tag::GPSLatitude,
+            Tag::GPSLatitudeRef,
+        ] {
+            assert!(exif.get_field(tag, In::PRIMARY).is_none(), "{tag}");
+        }
+        assert!(exif.fields().all(|field| field.ifd_num == In::PRIMARY));
+    }
+
+    #[test]
+    fn orientation_pixels() {
+        let (width, height) = (3, 2);
+        let stored = GrayImage::from_fn(width, height, |x, y| image::Luma([(10 * (x + width * y)) as u8]));
+        // Where the stored pixel `x`,`y` displays, from the side each orientation puts the first row and column on
+        let displayed = |orientation, x, y| match orientation {
+            1 => (x, y),
+            2 => (width - 1 - x, y),
+            3 => (width - 1 - x, height - 1 - y),
+            4 => (x, height - 1 - y),
+            5 => (y, x),
+            6 => (height - 1 - y, x),
+            7 => (height - 1 - y, width - 1 - x),
+            8 => (y, width - 1 - x),
+            _ => unreachable!(),
+        };
+
+        for orientation in 1..=8 {
+            let upright = apply_orientation(DynamicImage::ImageLuma8(stored.clone()), orientation).into_luma8();
+            for (x, y, pixel) in stored.enumerate_pixels() {
+                let (x, y) = displayed(orientation, x, y);
+                assert_eq!(upright.get_pixel(x, y), pixel, "orientation {orientation}");
+            }
+        }
+    }
+}
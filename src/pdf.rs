@@ -0,0 +1,91 @@
+use std::{fmt, sync::OnceLock};
+
+use image::DynamicImage;
+use pdfium_render::prelude::*;
+
+#[derive(Debug)]
+pub enum PdfError {
+    /// The pdfium library couldn't be loaded
+    Unavailable,
+    Pdfium(PdfiumError),
+    /// 1-based page requested, and the page count of the document
+    PageOutOfRange(u16, u16),
+}
+
+impl fmt::Display for PdfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PdfError::Unavailable => f.write_str("pdfium library is not available"),
+            PdfError::Pdfium(err) => write!(f, "{err}"),
+            PdfError::PageOutOfRange(page, count) => write!(f, "Page {page} is outside of the {count} pages document"),
+        }
+    }
+}
+
+impl From<PdfiumError> for PdfError {
+    fn from(err: PdfiumError) -> Self {
+        PdfError::Pdfium(err)
+    }
+}
+
+pub fn is_pdf(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"%PDF-")
+}
+
+/// Page of a PDF document, rendered through the system pdfium library (calls to it are serialized)
+pub struct Page<'a> {
+    document: PdfDocument<'a>,
+    index: u16,
+}
+
+impl<'a> Page<'a> {
+    /// Open the 1-based `page` of the document
+    pub fn open(bytes: &'a [u8], page: u16) -> Result<Self, PdfError> {
+        let document = pdfium()
+            .ok_or(PdfError::Unavailable)?
+            .load_pdf_from_byte_slice(bytes, None)?;
+        let count = document.pages().len();
+        if page == 0 || page > count {
+            return Err(PdfError::PageOutOfRange(page, count));
+        }
+
+        Ok(Self {
+            document,
+            index: page - 1,
+        })
+    }
+
+    /// Size of the page in pixels at 72 DPI, one pixel per point
+    pub fn size(&self) -> Result<(u32, u32), PdfError> {
+        let page = self.document.pages().get(self.index)?;
+        Ok((
+            page.width().value.ceil().max(1.0) as u32,
+            page.height().value.ceil().max(1.0) as u32,
+        ))
+    }
+
+    /// Render the whole page, on white, at the given size
+    pub fn render(&self, width: u32, height: u32) -> Result<DynamicImage, PdfError> {
+        let config = PdfRenderConfig::new()
+            .set_target_size(width as Pixels, height as Pixels)
+            .render_form_data(true);
+        let page = self.document.pages().get(self.index)?;
+        let bitmap = page.render_with_config(&config)?;
+
+        // Pages are opaque, dropping the alpha channel lets the output default to JPEG
+        Ok(DynamicImage::ImageRgb8(bitmap.as_image().to_rgb8()))
+    }
+}
+
+fn pdfium() -> Option<&'static Pdfium> {
+    static PDFIUM: OnceLock<Option<Pdfium>> = OnceLock::new();
+    PDFIUM
+        .get_or_init(|| match Pdfium::bind_to_system_library() {
+            Ok(bindings) => Some(Pdfium::new(bindings)),
+            Err(err) => {
+                tracing::error!("Load pdfium library error {err}");
+                None
+            }
+        })
+        .as_ref()
+}
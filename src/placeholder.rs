@@ -0,0 +1,144 @@
+use std::num::NonZeroU32;
+
+use fast_image_resize as fir;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+/// Longest side images are downsampled to before computing a placeholder, plenty for a handful of components
+const THUMBNAIL_SIZE: u32 = 32;
+/// Longest side images are downsampled to before looking for their color
+const COLOR_THUMBNAIL_SIZE: u32 = 16;
+
+/// Summary of an image, answered instead of the image itself
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum Placeholder {
+    BlurHash(BlurHash),
+    Color(Color),
+}
+
+#[derive(Debug, Serialize)]
+pub struct BlurHash {
+    pub hash: String,
+    /// Dimensions of the source, for the client to reserve the right aspect ratio
+    pub width: u32,
+    pub height: u32,
+}
+
+pub fn blurhash(image: &DynamicImage, components_x: u32, components_y: u32) -> Result<BlurHash, blurhash::Error> {
+    let thumbnail = thumbnail(image, THUMBNAIL_SIZE);
+    let hash = blurhash::encode(
+        components_x,
+        components_y,
+        thumbnail.width().get(),
+        thumbnail.height().get(),
+        thumbnail.buffer(),
+    )?;
+
+    Ok(BlurHash {
+        hash,
+        width: image.width(),
+        height: image.height(),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ColorMode {
+    /// Most represented color, the centroid of the largest k-means cluster
+    Dominant,
+    /// Mean of all the pixels
+    Average,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Color {
+    /// `#rrggbb`
+    pub hex: String,
+}
+
+pub fn color(image: &DynamicImage, mode: ColorMode) -> Color {
+    let thumbnail = thumbnail(image, COLOR_THUMBNAIL_SIZE);
+    // Fully transparent pixels have no meaningful color, the others count regardless of their opacity
+    let pixels = thumbnail
+        .buffer()
+        .chunks_exact(4)
+        .filter(|pixel| pixel[3] > 0)
+        .map(|pixel| [pixel[0] as f32, pixel[1] as f32, pixel[2] as f32])
+        .collect::<Vec<_>>();
+
+    let [r, g, b] = match mode {
+        _ if pixels.is_empty() => [0.0; 3],
+        ColorMode::Average => mean(&pixels),
+        ColorMode::Dominant => dominant(&pixels),
+    };
+
+    Color {
+        hex: format!("#{:02x}{:02x}{:02x}", r.round() as u8, g.round() as u8, b.round() as u8),
+    }
+}
+
+fn mean(pixels: &[[f32; 3]]) -> [f32; 3] {
+    let mut sum = [0.0; 3];
+    for pixel in pixels {
+        for (sum, channel) in sum.iter_mut().zip(pixel) {
+            *sum += channel;
+        }
+    }
+
+    sum.map(|sum| sum / pixels.len() as f32)
+}
+
+/// Centroid of the largest cluster after a few k-means iterations, seeded with evenly spread pixels
+fn dominant(pixels: &[[f32; 3]]) -> [f32; 3] {
+    const CLUSTERS: usize = 4;
+    const ITERATIONS: usize = 8;
+
+    let distance = |a: &[f32; 3], b: &[f32; 3]| (0..3).map(|i| (a[i] - b[i]).powi(2)).sum::<f32>();
+    let nearest = |centroids: &[[f32; 3]], pixel: &[f32; 3]| {
+        (0..centroids.len())
+            .min_by(|&a, &b| distance(&centroids[a], pixel).total_cmp(&distance(&centroids[b], pixel)))
+            .unwrap()
+    };
+
+    let mut centroids = (0..CLUSTERS.min(pixels.len()))
+        .map(|i| pixels[i * pixels.len() / CLUSTERS.min(pixels.len())])
+        .collect::<Vec<_>>();
+    let mut clusters = vec![Vec::new(); centroids.len()];
+    for _ in 0..ITERATIONS {
+        clusters.iter_mut().for_each(Vec::clear);
+        for pixel in pixels {
+            clusters[nearest(&centroids, pixel)].push(*pixel);
+        }
+        for (centroid, cluster) in centroids.iter_mut().zip(&clusters) {
+            if !cluster.is_empty() {
+                *centroid = mean(cluster);
+            }
+        }
+    }
+
+    let largest = (0..clusters.len()).max_by_key(|&i| clusters[i].len()).unwrap();
+    centroids[largest]
+}
+
+/// Downsample to RGBA with at most `size` pixels on the longest side
+fn thumbnail(image: &DynamicImage, size: u32) -> fir::Image<'static> {
+    let (width, height) = (image.width(), image.height());
+    let src_image = fir::Image::from_vec_u8(
+        NonZeroU32::new(width).unwrap(),
+        NonZeroU32::new(height).unwrap(),
+        image.to_rgba8().into_raw(),
+        fir::PixelType::U8x4,
+    )
+    .unwrap();
+
+    let ratio = f32::min(1.0, size as f32 / width.max(height) as f32);
+    let scaled = |value: u32| NonZeroU32::new(((value as f32 * ratio).round() as u32).max(1)).unwrap();
+    let mut dst_image = fir::Image::new(scaled(width), scaled(height), fir::PixelType::U8x4);
+
+    fir::Resizer::new(fir::ResizeAlg::Convolution(fir::FilterType::Box))
+        .resize(&src_image.view(), &mut dst_image.view_mut())
+        .expect("source and destination have the same pixel type");
+
+    dst_image
+}
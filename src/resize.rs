@@ -0,0 +1,594 @@
+use std::{num::NonZeroU32, str::FromStr};
+
+use fast_image_resize as fir;
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{color::Color, depth, linear};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FitMode {
+    /// Scale to fit within the requested box, preserving the aspect ratio
+    Contain,
+    /// Scale to fill the requested box, preserving the aspect ratio and cropping the overflow
+    Cover,
+    /// Stretch to the exact requested dimensions
+    Fill,
+    /// Scale to fit within the requested box like `contain`, then center on a canvas of exactly its dimensions
+    Pad,
+}
+
+/// Resampling filter, from the slowest and sharpest to the fastest and blockiest
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    /// Best quality, the default
+    Lanczos3,
+    /// Close to `lanczos3` on downscales for less CPU
+    CatmullRom,
+    /// Noticeably softer, fast enough for thumbnails
+    Bilinear,
+    /// Averages the covered pixels, fastest convolution for large downscales
+    Box,
+    /// No interpolation at all, keeps hard edges of pixel art
+    Nearest,
+}
+
+impl ResizeFilter {
+    pub fn algorithm(self) -> fir::ResizeAlg {
+        match self {
+            ResizeFilter::Lanczos3 => fir::ResizeAlg::Convolution(fir::FilterType::Lanczos3),
+            ResizeFilter::CatmullRom => fir::ResizeAlg::Convolution(fir::FilterType::CatmullRom),
+            ResizeFilter::Bilinear => fir::ResizeAlg::Convolution(fir::FilterType::Bilinear),
+            ResizeFilter::Box => fir::ResizeAlg::Convolution(fir::FilterType::Box),
+            ResizeFilter::Nearest => fir::ResizeAlg::Nearest,
+        }
+    }
+}
+
+/// Part of the image kept when `fit=cover` crops the overflow
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    Northeast,
+    Northwest,
+    Southeast,
+    Southwest,
+    /// The most detailed part of the image, by edge energy, centered when it is evenly detailed
+    Smart,
+}
+
+impl Gravity {
+    /// Horizontal and vertical position of the kept region, from 0 (left/top) to 1 (right/bottom)
+    pub fn centering(self) -> (f32, f32) {
+        match self {
+            // Moved over the detailed part afterwards, from the source pixels
+            Gravity::Center | Gravity::Smart => (0.5, 0.5),
+            Gravity::North => (0.5, 0.0),
+            Gravity::South => (0.5, 1.0),
+            Gravity::East => (1.0, 0.5),
+            Gravity::West => (0.0, 0.5),
+            Gravity::Northeast => (1.0, 0.0),
+            Gravity::Northwest => (0.0, 0.0),
+            Gravity::Southeast => (1.0, 1.0),
+            Gravity::Southwest => (0.0, 1.0),
+        }
+    }
+}
+
+/// Point of the source kept in sight by `fit=cover`, given as `x,y` fractions of its width and height
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FocalPoint {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl FromStr for FocalPoint {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts = value
+            .split(',')
+            .map(|part| part.trim().parse::<f32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid focal point `{value}`: {err}"))?;
+
+        match parts[..] {
+            [x, y] if (0.0..=1.0).contains(&x) && (0.0..=1.0).contains(&y) => Ok(FocalPoint { x, y }),
+            [_, _] => Err(format!("focal point `{value}` must be between 0 and 1 on both axes")),
+            _ => Err(format!("invalid focal point `{value}`, expected `x,y`")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for FocalPoint {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Source region given as `x,y,width,height`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CropRect {
+    pub x: u32,
+    pub y: u32,
+    pub width: NonZeroU32,
+    pub height: NonZeroU32,
+}
+
+impl FromStr for CropRect {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let parts = value
+            .split(',')
+            .map(|part| part.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid crop `{value}`: {err}"))?;
+
+        match parts[..] {
+            [x, y, width, height] => Ok(CropRect {
+                x,
+                y,
+                width: NonZeroU32::new(width).ok_or("crop width must not be zero")?,
+                height: NonZeroU32::new(height).ok_or("crop height must not be zero")?,
+            }),
+            _ => Err(format!("invalid crop `{value}`, expected `x,y,width,height`")),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for CropRect {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ResizeOptions {
+    pub width: Option<NonZeroU32>,
+    pub height: Option<NonZeroU32>,
+    pub fit: Option<FitMode>,
+    pub gravity: Option<Gravity>,
+    /// Point of the source (or its crop) the `fit=cover` crop is centered on, as far as it fits. Wins over `gravity`
+    pub focal: Option<FocalPoint>,
+    pub crop: Option<CropRect>,
+    /// Device pixel ratio multiplying the requested dimensions, clamped to 1-4
+    pub dpr: Option<f32>,
+    /// Pad `fit=contain` outputs to the requested box instead of shrinking them to the image
+    pub pad: bool,
+    /// Scale of the source when no dimension is requested, the source size when absent
+    pub default_scale: Option<f32>,
+    /// Shrink outputs larger than the source (or its crop) to it, the aspect ratio and the padding canvas kept
+    pub no_upscale: bool,
+    /// Tolerance (in percent) of the uniform border cut away from the source (or its crop) before resizing, see
+    /// `trim::bounds`. Left to the caller, the plan takes the trimmed region as the crop.
+    pub trim: Option<f32>,
+}
+
+impl ResizeOptions {
+    /// Requested `width` and `height` in output pixels, the `dpr` applied
+    pub fn requested(&self) -> (Option<u32>, Option<u32>) {
+        let dpr = self
+            .dpr
+            .filter(|dpr| !dpr.is_nan())
+            .map_or(1.0, |dpr| dpr.clamp(1.0, 4.0));
+        let requested =
+            |value: Option<NonZeroU32>| value.map(|value| ((value.get() as f32 * dpr).round() as u32).max(1));
+        (requested(self.width), requested(self.height))
+    }
+}
+
+#[derive(Debug)]
+pub struct ResizePlan {
+    pub width: NonZeroU32,
+    pub height: NonZeroU32,
+    /// Region of the source to resize from, the whole source when absent
+    pub crop: Option<fir::CropBox>,
+    /// Dimensions of the canvas the resized image is centered on, when padded
+    pub canvas: Option<(NonZeroU32, NonZeroU32)>,
+}
+
+/// Largest output the server agrees to allocate
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_width: u32,
+    pub max_height: u32,
+    pub max_pixels: u64,
+}
+
+impl ResizePlan {
+    pub fn check(&self, limits: &Limits) -> Result<(), String> {
+        let (width, height) = self
+            .canvas
+            .map_or((self.width.get(), self.height.get()), |(width, height)| {
+                (width.get(), height.get())
+            });
+        if width > limits.max_width || height > limits.max_height {
+            return Err(format!(
+                "Output {width}x{height} exceeds the maximum of {}x{}",
+                limits.max_width, limits.max_height
+            ));
+        }
+        if width as u64 * height as u64 > limits.max_pixels {
+            return Err(format!(
+                "Output {width}x{height} exceeds the maximum of {} pixels",
+                limits.max_pixels
+            ));
+        }
+
+        Ok(())
+    }
+}
+
+/// Compute the output dimensions, and the source crop if any, for the requested options.
+///
+/// The requested `crop` is applied first and acts as the source for everything else. When only one dimension
+/// is requested the other is derived from the source aspect ratio, whatever the fit. Without any dimension the
+/// source is scaled by `default_scale`.
+pub fn plan(src_width: NonZeroU32, src_height: NonZeroU32, options: &ResizeOptions) -> Result<ResizePlan, String> {
+    let region = match options.crop {
+        Some(crop) => {
+            let right = crop.x as u64 + crop.width.get() as u64;
+            let bottom = crop.y as u64 + crop.height.get() as u64;
+            if right > src_width.get() as u64 || bottom > src_height.get() as u64 {
+                return Err(format!(
+                    "Crop {},{},{},{} is outside of the {src_width}x{src_height} source",
+                    crop.x, crop.y, crop.width, crop.height
+                ));
+            }
+
+            Some(fir::CropBox {
+                left: crop.x,
+                top: crop.y,
+                width: crop.width,
+                height: crop.height,
+            })
+        }
+        None => None,
+    };
+
+    let (src_width, src_height) = region.map_or((src_width.get(), src_height.get()), |region| {
+        (region.width.get(), region.height.get())
+    });
+    let scaled = |value: u32, ratio: f32| (value as f32 * ratio) as u32;
+
+    let mut crop = region;
+    let mut canvas = None;
+    let (width, height) = match options.requested() {
+        (Some(width), Some(height)) => match options.fit.unwrap_or(FitMode::Fill) {
+            FitMode::Fill => (width, height),
+            fit @ (FitMode::Contain | FitMode::Pad) => {
+                let ratio = f32::min(width as f32 / src_width as f32, height as f32 / src_height as f32);
+                if options.pad || fit == FitMode::Pad {
+                    canvas = Some((NonZeroU32::new(width).unwrap(), NonZeroU32::new(height).unwrap()));
+                }
+                (scaled(src_width, ratio), scaled(src_height, ratio))
+            }
+            FitMode::Cover => {
+                let centering = options.gravity.unwrap_or(Gravity::Center).centering();
+                let mut cover = cover_crop(src_width, src_height, width, height, centering);
+                if let Some(focal) = options.focal {
+                    cover.left = centered_on(focal.x, src_width, cover.width.get());
+                    cover.top = centered_on(focal.y, src_height, cover.height.get());
+                }
+                if let Some(region) = region {
+                    cover.left += region.left;
+                    cover.top += region.top;
+                }
+
+                crop = Some(cover);
+                (width, height)
+            }
+        },
+        (Some(width), None) => (width, scaled(src_height, width as f32 / src_width as f32)),
+        (None, Some(height)) => (scaled(src_width, height as f32 / src_height as f32), height),
+        (None, None) => {
+            let scale = options.default_scale.unwrap_or(1.0);
+            (scaled(src_width, scale), scaled(src_height, scale))
+        }
+    };
+    let (width, height) = match (options.no_upscale, crop) {
+        (false, _) => (width, height),
+        // `cover` resizes from its crop, the others from the whole region
+        (true, Some(crop)) => within(width, height, crop.width.get(), crop.height.get()),
+        (true, None) => within(width, height, src_width, src_height),
+    };
+
+    Ok(ResizePlan {
+        width: NonZeroU32::new(width.max(1)).unwrap(),
+        height: NonZeroU32::new(height.max(1)).unwrap(),
+        crop,
+        canvas,
+    })
+}
+
+/// Scale `width`x`height` down, keeping its aspect ratio, until it fits in `max_width`x`max_height`
+fn within(width: u32, height: u32, max_width: u32, max_height: u32) -> (u32, u32) {
+    let ratio = f32::min(
+        1.0,
+        f32::min(max_width as f32 / width as f32, max_height as f32 / height as f32),
+    );
+    if ratio == 1.0 {
+        return (width, height);
+    }
+
+    (
+        ((width as f32 * ratio).round() as u32).min(max_width),
+        ((height as f32 * ratio).round() as u32).min(max_height),
+    )
+}
+
+/// Resize the `crop` of `image` to `width`x`height`, averaging linear light when `linear` (pixels then come back in
+/// the depth of `image`). Colors are premultiplied by their alpha meanwhile, the ones of transparent pixels would
+/// otherwise bleed dark fringes into the edges.
+pub fn resample(
+    resizer: &mut fir::Resizer,
+    image: &fir::Image,
+    crop: Option<fir::CropBox>,
+    width: NonZeroU32,
+    height: NonZeroU32,
+    linear: bool,
+) -> Result<fir::Image<'static>, fir::DifferentTypesOfPixelsError> {
+    let mul_div = fir::MulDiv::default();
+    let has_alpha = depth::has_alpha(image);
+
+    // The linear copy is already cropped
+    let mut source = linear.then(|| linear::linearize(image, crop));
+    if has_alpha {
+        source = Some(match source {
+            Some(mut source) => {
+                mul_div
+                    .multiply_alpha_inplace(&mut source.view_mut())
+                    .expect("RGBA pixels are supported");
+                source
+            }
+            None => {
+                let mut premultiplied = fir::Image::new(image.width(), image.height(), image.pixel_type());
+                mul_div
+                    .multiply_alpha(&image.view(), &mut premultiplied.view_mut())
+                    .expect("source and destination have the same size and pixel type");
+                premultiplied
+            }
+        });
+    }
+
+    let mut src_view = source.as_ref().unwrap_or(image).view();
+    if let (false, Some(crop)) = (linear, crop) {
+        src_view.set_crop_box(crop).unwrap();
+    }
+    let mut resized = fir::Image::new(width, height, src_view.pixel_type());
+    resizer.resize(&src_view, &mut resized.view_mut())?;
+    if has_alpha {
+        mul_div
+            .divide_alpha_inplace(&mut resized.view_mut())
+            .expect("RGBA pixels are supported");
+    }
+
+    Ok(if linear {
+        linear::delinearize(&resized, depth::is_16_bit(image))
+    } else {
+        resized
+    })
+}
+
+/// Center `image` on a `width`x`height` canvas filled with `background`, RGBA unless both are opaque. The canvas has
+/// the bit depth of `image`.
+pub fn pad(image: &fir::Image, width: NonZeroU32, height: NonZeroU32, background: Color) -> fir::Image<'static> {
+    let deep = depth::is_16_bit(image);
+    let has_alpha = depth::has_alpha(image) || !background.is_opaque();
+    let (pixel_type, channels) = match (deep, has_alpha) {
+        (true, true) => (fir::PixelType::U16x4, 4),
+        (true, false) => (fir::PixelType::U16x3, 3),
+        (false, true) => (fir::PixelType::U8x4, 4),
+        (false, false) => (fir::PixelType::U8x3, 3),
+    };
+    let channel_len = if deep { 2 } else { 1 };
+    let fill = background.0[..channels]
+        .iter()
+        .flat_map(|&value| {
+            if deep {
+                (value as u16 * 257).to_ne_bytes().to_vec()
+            } else {
+                vec![value]
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut buffer = fill.repeat(width.get() as usize * height.get() as usize);
+    let src_channels = if depth::has_alpha(image) { 4 } else { 3 };
+    let (pixel_len, src_pixel_len) = (channels * channel_len, src_channels * channel_len);
+    let left = (width.get().saturating_sub(image.width().get()) / 2) as usize;
+    let top = (height.get().saturating_sub(image.height().get()) / 2) as usize;
+    let row_len = width.get() as usize * pixel_len;
+    let (color_len, opaque) = (3 * channel_len, [u8::MAX; 2]);
+
+    for (y, row) in image
+        .buffer()
+        .chunks_exact(image.width().get() as usize * src_pixel_len)
+        .enumerate()
+    {
+        let start = (top + y) * row_len + left * pixel_len;
+        let dst = buffer[start..start + image.width().get() as usize * pixel_len].chunks_exact_mut(pixel_len);
+        for (dst, src) in dst.zip(row.chunks_exact(src_pixel_len)) {
+            dst[..color_len].copy_from_slice(&src[..color_len]);
+            if channels == 4 {
+                let alpha = if src_channels == 4 {
+                    &src[color_len..]
+                } else {
+                    &opaque[..channel_len]
+                };
+                dst[color_len..].copy_from_slice(alpha);
+            }
+        }
+    }
+
+    fir::Image::from_vec_u8(width, height, buffer, pixel_type).unwrap()
+}
+
+/// Start of the `window` centered on the `point` fraction of `extent`, kept within it
+fn centered_on(point: f32, extent: u32, window: u32) -> u32 {
+    let start = (point * extent as f32 - window as f32 / 2.0).round().max(0.0) as u32;
+    start.min(extent - window)
+}
+
+/// Largest region of the source having the aspect ratio of the output, positioned by `centering`
+fn cover_crop(src_width: u32, src_height: u32, width: u32, height: u32, centering: (f32, f32)) -> fir::CropBox {
+    let src_ratio = src_width as f32 / src_height as f32;
+    let ratio = width as f32 / height as f32;
+
+    let (crop_width, crop_height) = if src_ratio > ratio {
+        (
+            ((src_height as f32 * ratio).round() as u32).clamp(1, src_width),
+            src_height,
+        )
+    } else {
+        (
+            src_width,
+            ((src_width as f32 / ratio).round() as u32).clamp(1, src_height),
+        )
+    };
+
+    fir::CropBox {
+        left: ((src_width - crop_width) as f32 * centering.0).round() as u32,
+        top: ((src_height - crop_height) as f32 * centering.1).round() as u32,
+        width: NonZeroU32::new(crop_width).unwrap(),
+        height: NonZeroU32::new(crop_height).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: Limits = Limits {
+        max_width: 4096,
+        max_height: 4096,
+        max_pixels: 10_000_000,
+    };
+
+    fn size(value: u32) -> NonZeroU32 {
+        NonZeroU32::new(value).unwrap()
+    }
+
+    fn options(width: Option<u32>, height: Option<u32>, fit: Option<FitMode>) -> ResizeOptions {
+        ResizeOptions {
+            width: width.and_then(NonZeroU32::new),
+            height: height.and_then(NonZeroU32::new),
+            fit,
+            ..Default::default()
+        }
+    }
+
+    fn planned(plan: &ResizePlan) -> (u32, u32) {
+        (plan.width.get(), plan.height.get())
+    }
+
+    #[test]
+    fn plan_dimensions() {
+        let (width, height) = (size(400), size(200));
+        let plan = |options| plan(width, height, &options).unwrap();
+
+        assert_eq!(planned(&plan(options(Some(100), None, None))), (100, 50));
+        assert_eq!(planned(&plan(options(None, Some(100), None))), (200, 100));
+        assert_eq!(planned(&plan(options(Some(100), Some(100), None))), (100, 100));
+        assert_eq!(
+            planned(&plan(options(Some(100), Some(100), Some(FitMode::Contain)))),
+            (100, 50)
+        );
+
+        let cover = plan(options(Some(100), Some(100), Some(FitMode::Cover)));
+        assert_eq!(planned(&cover), (100, 100));
+        let crop = cover.crop.unwrap();
+        assert_eq!(
+            (crop.left, crop.top, crop.width.get(), crop.height.get()),
+            (100, 0, 200, 200)
+        );
+
+        let pad = plan(options(Some(100), Some(100), Some(FitMode::Pad)));
+        assert_eq!(planned(&pad), (100, 50));
+        assert_eq!(pad.canvas, Some((size(100), size(100))));
+
+        let dpr = ResizeOptions {
+            dpr: Some(2.0),
+            ..options(Some(100), None, None)
+        };
+        assert_eq!(planned(&plan(dpr)), (200, 100));
+        let no_upscale = ResizeOptions {
+            no_upscale: true,
+            ..options(Some(1000), None, None)
+        };
+        assert_eq!(planned(&plan(no_upscale)), (400, 200));
+    }
+
+    #[test]
+    fn plan_outside_crop() {
+        let crop = ResizeOptions {
+            crop: Some(CropRect {
+                x: 300,
+                y: 0,
+                width: size(200),
+                height: size(100),
+            }),
+            ..Default::default()
+        };
+        assert!(plan(size(400), size(200), &crop).is_err());
+    }
+
+    #[test]
+    fn plan_over_limits() {
+        let checked = |options: ResizeOptions| plan(size(400), size(200), &options).unwrap().check(&LIMITS);
+
+        assert!(checked(options(Some(4096), Some(2048), None)).is_ok());
+        // Rejected from the dimensions alone, before anything is allocated
+        assert!(checked(options(Some(50_000), Some(50_000), None)).is_err());
+        assert!(checked(options(Some(4097), None, None)).is_err());
+        assert!(checked(options(None, Some(4097), None)).is_err());
+        assert!(checked(options(Some(4000), Some(4000), None)).is_err_and(|err| err.contains("pixels")));
+        // The padding canvas counts, not the image centered on it
+        assert!(checked(options(Some(5000), Some(100), Some(FitMode::Pad))).is_err());
+        // and the `dpr` multiplies the requested dimensions
+        let dpr = ResizeOptions {
+            dpr: Some(4.0),
+            ..options(Some(2000), None, None)
+        };
+        assert!(checked(dpr).is_err());
+    }
+
+    #[test]
+    fn resample_no_dark_fringe() {
+        // Opaque white on the left, transparent black on the right, the edge falling inside of output pixels
+        let (width, height) = (size(63), size(8));
+        let pixels = (0..height.get())
+            .flat_map(|_| (0..width.get()).map(|x| if x < 30 { [255; 4] } else { [0; 4] }))
+            .flatten()
+            .collect();
+        let image = fir::Image::from_vec_u8(width, height, pixels, fir::PixelType::U8x4).unwrap();
+
+        for linear in [false, true] {
+            let mut resizer = fir::Resizer::new(ResizeFilter::Lanczos3.algorithm());
+            // SAFETY: no extension is supported by every CPU. The AVX2 convolution of RGBA pixels reads its i16
+            // coefficients as misaligned i32, which debug builds abort on.
+            unsafe { resizer.set_cpu_extensions(fir::CpuExtensions::None) };
+            let resized = resample(&mut resizer, &image, None, size(16), size(2), linear).unwrap();
+            for pixel in resized.buffer().chunks_exact(4) {
+                // Only the alpha fades, visible pixels stay white
+                if pixel[3] > 8 {
+                    assert!(
+                        pixel[..3].iter().all(|&channel| channel >= 250),
+                        "{pixel:?} linear {linear}"
+                    );
+                }
+            }
+            assert!(resized
+                .buffer()
+                .chunks_exact(4)
+                .any(|pixel| (9..255).contains(&pixel[3])));
+        }
+    }
+}
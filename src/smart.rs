@@ -0,0 +1,133 @@
+use std::num::NonZeroU32;
+
+use fast_image_resize as fir;
+
+use crate::resize::CropRect;
+
+/// Longest side of the downscaled copy the edges are measured on
+const ANALYSIS_SIZE: u32 = 128;
+/// Edge energy the best window needs over the centered one, below it the image is considered evenly detailed
+const MIN_GAIN: f32 = 1.1;
+
+/// Move the `fit=cover` window `crop` over the part of the source (or of its requested `region`) with the most edge
+/// energy. `None` when the window can't move or the heuristic is inconclusive, the centered crop then stays.
+pub fn position(image: &fir::Image, region: Option<CropRect>, crop: fir::CropBox) -> Option<fir::CropBox> {
+    let region = region.map_or(
+        fir::CropBox {
+            left: 0,
+            top: 0,
+            width: image.width(),
+            height: image.height(),
+        },
+        |region| fir::CropBox {
+            left: region.x,
+            top: region.y,
+            width: region.width,
+            height: region.height,
+        },
+    );
+    // A cover window spans the whole region along one of the axes
+    let horizontal = crop.width < region.width;
+    if !horizontal && crop.height >= region.height {
+        return None;
+    }
+
+    let (energy, analysis_width, analysis_height) = edge_energy(image, region);
+    let (profile, len, region_len, window_len) = if horizontal {
+        let columns = (0..analysis_width)
+            .map(|x| (0..analysis_height).map(|y| energy[y * analysis_width + x]).sum())
+            .collect::<Vec<f32>>();
+        (columns, analysis_width, region.width.get(), crop.width.get())
+    } else {
+        let rows = energy
+            .chunks_exact(analysis_width)
+            .map(|row| row.iter().sum())
+            .collect::<Vec<f32>>();
+        (rows, analysis_height, region.height.get(), crop.height.get())
+    };
+
+    let window = ((window_len as f32 * len as f32 / region_len as f32).round() as usize).clamp(1, len);
+    let start = best_window(&profile, window)?;
+    let offset = ((start as f32 * region_len as f32 / len as f32).round() as u32).min(region_len - window_len);
+
+    Some(if horizontal {
+        fir::CropBox {
+            left: region.left + offset,
+            ..crop
+        }
+    } else {
+        fir::CropBox {
+            top: region.top + offset,
+            ..crop
+        }
+    })
+}
+
+/// Gradient magnitude of the luma of a downscaled copy of `region`, row by row, with the copy's dimensions
+fn edge_energy(image: &fir::Image, region: fir::CropBox) -> (Vec<f32>, usize, usize) {
+    let ratio = f32::min(
+        1.0,
+        ANALYSIS_SIZE as f32 / region.width.get().max(region.height.get()) as f32,
+    );
+    let scaled = |value: NonZeroU32| NonZeroU32::new(((value.get() as f32 * ratio).round() as u32).max(1)).unwrap();
+    let (width, height) = (scaled(region.width), scaled(region.height));
+
+    let mut view = image.view();
+    view.set_crop_box(region).expect("the region is inside the image");
+    let mut thumbnail = fir::Image::new(width, height, image.pixel_type());
+    fir::Resizer::new(fir::ResizeAlg::Convolution(fir::FilterType::Bilinear))
+        .resize(&view, &mut thumbnail.view_mut())
+        .expect("source and destination have the same pixel type");
+
+    // Transparent pixels carry no detail
+    let channels = if image.pixel_type() == fir::PixelType::U8x4 {
+        4
+    } else {
+        3
+    };
+    let luma = thumbnail
+        .buffer()
+        .chunks_exact(channels)
+        .map(|pixel| {
+            let luma = 0.2126 * pixel[0] as f32 + 0.7152 * pixel[1] as f32 + 0.0722 * pixel[2] as f32;
+            luma * pixel.get(3).map_or(1.0, |&alpha| alpha as f32 / 255.0)
+        })
+        .collect::<Vec<_>>();
+
+    let (width, height) = (width.get() as usize, height.get() as usize);
+    let mut energy = vec![0.0; width * height];
+    for y in 0..height {
+        for x in 0..width {
+            let i = y * width + x;
+            let dx = if x + 1 < width { luma[i + 1] - luma[i] } else { 0.0 };
+            let dy = if y + 1 < height { luma[i + width] - luma[i] } else { 0.0 };
+            energy[i] = dx.abs() + dy.abs();
+        }
+    }
+
+    (energy, width, height)
+}
+
+/// Start of the `window` long run of `profile` with the largest sum, `None` when it isn't clearly better than the
+/// centered one
+fn best_window(profile: &[f32], window: usize) -> Option<usize> {
+    let mut sums = Vec::with_capacity(profile.len() - window + 1);
+    let mut sum = profile[..window].iter().sum::<f32>();
+    sums.push(sum);
+    for start in 1..=profile.len() - window {
+        sum += profile[start + window - 1] - profile[start - 1];
+        sums.push(sum);
+    }
+
+    let centered = sums[(sums.len() - 1) / 2];
+    let (best, &best_sum) = sums
+        .iter()
+        .enumerate()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .expect("there is at least one window");
+    if best_sum <= 0.0 || best_sum < centered * MIN_GAIN {
+        return None;
+    }
+
+    Some(best)
+}
@@ -0,0 +1,636 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use object_store::{aws::AmazonS3, GetOptions, ObjectStore};
+use percent_encoding::{AsciiSet, CONTROLS};
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue},
+    Client, StatusCode,
+};
+use serde::Deserialize;
+
+use crate::cache::{MemoryCache, Weigh};
+
+/// Wait before the first retry of a failed fetch, doubled for each of the next ones
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
+/// Bytes escaped in the path segments of origin URLs: the path percent-encode set of the URL standard, with `/`
+/// and `%` since the path was decoded. Non-ASCII characters are always escaped, as UTF-8.
+const PATH_SEGMENT: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}')
+    .add(b'/')
+    .add(b'%');
+
+/// Origin the source images are fetched from
+#[async_trait]
+pub trait Source: Send + Sync {
+    /// Fetch the source image at `path`, percent-decoded. HTTP origins are sent the `forwarded` headers of the client
+    async fn fetch(&self, path: &str, forwarded: &HeaderMap) -> Result<Fetched, FetchError>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FetchError {
+    /// Doesn't exist
+    NotFound,
+    /// Would be fetched from a host outside of `--allowed-hosts`
+    Forbidden,
+    /// Larger than `--max-source-bytes`
+    TooLarge,
+    /// The origin failed with this status (a `5xx`, or a `4xx` other than `404` and `410`), or without an answer
+    Upstream(Option<StatusCode>),
+    /// The origin didn't answer in time
+    UpstreamTimeout,
+}
+
+/// Reject sources of `size` bytes over `max_bytes`, before their content is read
+fn check_size(path: &str, size: u64, max_bytes: Option<u64>) -> Result<(), FetchError> {
+    match max_bytes {
+        Some(max_bytes) if size > max_bytes => {
+            tracing::info!(path, size, "Source larger than {max_bytes} bytes");
+            Err(FetchError::TooLarge)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Source image, and how long its origin allows it to be cached
+pub struct Fetched {
+    pub data: Bytes,
+    /// From the origin `Cache-Control`, absent when it says nothing about caching
+    pub cache_policy: Option<CachePolicy>,
+    /// `Content-Type` declared by an HTTP origin
+    pub content_type: Option<HeaderValue>,
+}
+
+impl Fetched {
+    pub fn new(data: Bytes) -> Self {
+        Self {
+            data,
+            cache_policy: None,
+            content_type: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// `no-store` or `private`, shared caches must not keep it
+    NoStore,
+    /// Seconds shared caches may keep it, `0` for `no-cache` since they can't revalidate the outputs against the
+    /// origin
+    MaxAge(u64),
+}
+
+impl CachePolicy {
+    /// Parse a `Cache-Control` header, `s-maxage` taking precedence over `max-age`
+    pub fn parse(value: &str) -> Option<Self> {
+        let (mut no_store, mut no_cache, mut max_age, mut s_max_age) = (false, false, None, None);
+        for directive in value.split(',') {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name.trim(), Some(argument.trim().trim_matches('"'))),
+                None => (directive.trim(), None),
+            };
+            match name.to_ascii_lowercase().as_str() {
+                "no-store" | "private" => no_store = true,
+                "no-cache" => no_cache = true,
+                "max-age" => max_age = argument.and_then(|age| age.parse().ok()),
+                "s-maxage" => s_max_age = argument.and_then(|age| age.parse().ok()),
+                _ => {}
+            }
+        }
+
+        if no_store {
+            Some(CachePolicy::NoStore)
+        } else if no_cache {
+            Some(CachePolicy::MaxAge(0))
+        } else {
+            s_max_age.or(max_age).map(CachePolicy::MaxAge)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum SourceKind {
+    /// `--local-folder`
+    Local,
+    /// `--s3-bucket`
+    S3,
+    /// `--remote-cdn`
+    Remote,
+}
+
+/// Sources tried in order until one has the image, a forbidden fetch ends the chain. An origin failure doesn't,
+/// but it is what the chain fails with when no later source has the image either.
+pub struct SourceChain {
+    sources: Vec<Box<dyn Source>>,
+}
+
+impl SourceChain {
+    pub fn new(sources: Vec<Box<dyn Source>>) -> Self {
+        Self { sources }
+    }
+}
+
+#[async_trait]
+impl Source for SourceChain {
+    async fn fetch(&self, path: &str, forwarded: &HeaderMap) -> Result<Fetched, FetchError> {
+        let mut failure = None;
+        for source in &self.sources {
+            match source.fetch(path, forwarded).await {
+                Err(FetchError::NotFound) => continue,
+                Err(err @ (FetchError::Upstream(_) | FetchError::UpstreamTimeout)) => {
+                    failure.get_or_insert(err);
+                }
+                result => return result,
+            }
+        }
+
+        Err(failure.unwrap_or(FetchError::NotFound))
+    }
+}
+
+pub struct LocalSource {
+    folder: PathBuf,
+    max_bytes: Option<u64>,
+}
+
+impl LocalSource {
+    pub fn new(folder: impl Into<PathBuf>, max_bytes: Option<u64>) -> Self {
+        // Canonical so that the canonical paths of the files inside of it start with it
+        let folder = folder.into();
+        Self {
+            folder: std::fs::canonicalize(&folder).unwrap_or(folder),
+            max_bytes,
+        }
+    }
+}
+
+#[async_trait]
+impl Source for LocalSource {
+    async fn fetch(&self, path: &str, _forwarded: &HeaderMap) -> Result<Fetched, FetchError> {
+        // Pushing an absolute path would replace the folder, and `..` or symlinks could escape it
+        let file_path = match tokio::fs::canonicalize(self.folder.join(path.trim_start_matches('/'))).await {
+            Ok(file_path) if file_path.starts_with(&self.folder) => file_path,
+            Ok(_) => {
+                tracing::warn!(path, "Path outside of the local folder");
+                return Err(FetchError::NotFound);
+            }
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!(path, "Resolve local file error {err:#}");
+                }
+                return Err(FetchError::NotFound);
+            }
+        };
+
+        if let Ok(metadata) = tokio::fs::metadata(&file_path).await {
+            check_size(path, metadata.len(), self.max_bytes)?;
+        }
+        match tokio::fs::read(file_path).await {
+            Ok(data) => Ok(Fetched::new(Bytes::from(data))),
+            Err(err) => {
+                if err.kind() != std::io::ErrorKind::NotFound {
+                    tracing::error!(path, "Read local file error {err:#}");
+                }
+                Err(FetchError::NotFound)
+            }
+        }
+    }
+}
+
+pub struct HttpSource {
+    client: Client,
+    base_url: String,
+    /// Hosts sources may be fetched from, any when absent
+    allowed_hosts: Option<Vec<String>>,
+    /// Sources kept with their validators, revalidated with the origin instead of downloaded again
+    originals: Option<MemoryCache<Original>>,
+    retry: Retry,
+    max_bytes: Option<u64>,
+}
+
+/// How failed fetches are retried, on connection errors and `5xx` responses only
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub retries: u32,
+    /// Time the retries must fit in, waiting for a retry doesn't start once it would end past it
+    pub budget: Duration,
+}
+
+/// Source previously downloaded from the origin
+#[derive(Clone)]
+pub struct Original {
+    data: Bytes,
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    cache_policy: Option<CachePolicy>,
+    content_type: Option<HeaderValue>,
+}
+
+impl Weigh for Original {
+    fn size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl HttpSource {
+    pub fn new(
+        client: Client,
+        base_url: String,
+        allowed_hosts: Option<Vec<String>>,
+        originals: Option<MemoryCache<Original>>,
+        retry: Retry,
+        max_bytes: Option<u64>,
+    ) -> Self {
+        Self {
+            client,
+            base_url,
+            allowed_hosts,
+            originals,
+            retry,
+            max_bytes,
+        }
+    }
+
+    /// Whether `url` points to one of the allowed hosts, compared case insensitively
+    pub fn is_allowed(&self, url: &str) -> bool {
+        let url = reqwest::Url::parse(url).ok();
+        let host = url.as_ref().and_then(|url| url.host_str());
+        self.allowed_hosts.as_ref().is_none_or(|allowed_hosts| {
+            host.is_some_and(|host| allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host)))
+        })
+    }
+
+    /// URL of the decoded `path` at the origin, encoded again so that a decoded `%`, `?` or `#` stays in the path
+    ///
+    /// `None` for empty, `.` or `..` segments, which the URL would resolve and so could leave the base path
+    fn url(&self, path: &str) -> Option<String> {
+        let segments = path.trim_start_matches('/').split('/');
+        let mut encoded = Vec::new();
+        for segment in segments {
+            if matches!(segment, "" | "." | "..") {
+                return None;
+            }
+            encoded.push(percent_encoding::utf8_percent_encode(segment, PATH_SEGMENT).to_string());
+        }
+        let path = encoded.join("/");
+        if self.base_url.ends_with('/') {
+            Some(format!("{}{path}", self.base_url))
+        } else {
+            Some(format!("{}/{path}", self.base_url))
+        }
+    }
+}
+
+#[async_trait]
+impl Source for HttpSource {
+    async fn fetch(&self, path: &str, forwarded: &HeaderMap) -> Result<Fetched, FetchError> {
+        let Some(url) = self.url(path) else {
+            tracing::warn!(path, "Path outside of the remote base");
+            return Err(FetchError::NotFound);
+        };
+        if !self.is_allowed(&url) {
+            tracing::warn!(path, url, "Host not allowed");
+            return Err(FetchError::Forbidden);
+        }
+
+        let stored = self.originals.as_ref().and_then(|originals| originals.get(path));
+        let started = Instant::now();
+        let mut attempt = 0;
+        let result = loop {
+            let mut request = self.client.get(&url).headers(forwarded.clone());
+            if let Some(stored) = &stored {
+                if let Some(etag) = &stored.etag {
+                    request = request.header(header::IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &stored.last_modified {
+                    request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+
+            let result = request.send().await;
+            let error = match &result {
+                Ok(resp) if resp.status().is_server_error() => format!("status code {}", resp.status()),
+                Ok(_) => break result,
+                Err(err) => format!("{err:#}"),
+            };
+            let backoff = RETRY_BACKOFF * 2u32.saturating_pow(attempt);
+            if attempt >= self.retry.retries || started.elapsed() + backoff >= self.retry.budget {
+                break result;
+            }
+
+            attempt += 1;
+            tracing::debug!(
+                path,
+                attempt,
+                "Retrying fetch in {}ms after {error}",
+                backoff.as_millis()
+            );
+            metrics::counter!("image_resize_fetch_retries_total").increment(1);
+            tokio::time::sleep(backoff).await;
+        };
+
+        let err = match (result, stored) {
+            (Ok(resp), Some(stored)) if resp.status() == StatusCode::NOT_MODIFIED => {
+                tracing::debug!(path, "Source not modified");
+                metrics::counter!("image_resize_revalidated_total").increment(1);
+                // A `304` carries the up to date caching headers, if any
+                let cache_policy = cache_policy(resp.headers()).or(stored.cache_policy);
+                return Ok(Fetched {
+                    data: stored.data,
+                    cache_policy,
+                    content_type: stored.content_type,
+                });
+            }
+            (Ok(resp), _) if resp.status().is_success() => {
+                // Chunked responses have no length to check upfront, their body is checked as it comes
+                if let Some(len) = resp.content_length() {
+                    check_size(path, len, self.max_bytes)?;
+                }
+                let headers = resp.headers();
+                let cache_policy = cache_policy(headers);
+                let etag = headers.get(header::ETAG).cloned();
+                let last_modified = headers.get(header::LAST_MODIFIED).cloned();
+                let content_type = headers.get(header::CONTENT_TYPE).cloned();
+                match read_body(path, resp, self.max_bytes).await {
+                    Ok(data) => {
+                        let revalidable = etag.is_some() || last_modified.is_some();
+                        if let Some(originals) = self.originals.as_ref().filter(|_| revalidable) {
+                            if cache_policy != Some(CachePolicy::NoStore) {
+                                let original = Original {
+                                    data: data.clone(),
+                                    etag,
+                                    last_modified,
+                                    cache_policy,
+                                    content_type: content_type.clone(),
+                                };
+                                originals.put(path, original);
+                            }
+                        }
+                        return Ok(Fetched {
+                            data,
+                            cache_policy,
+                            content_type,
+                        });
+                    }
+                    Err(FetchError::TooLarge) => return Err(FetchError::TooLarge),
+                    Err(err) => err,
+                }
+            }
+            (Ok(resp), _) if matches!(resp.status(), StatusCode::NOT_FOUND | StatusCode::GONE) => {
+                tracing::debug!(path, "Source not found at the origin");
+                return Err(FetchError::NotFound);
+            }
+            (Ok(resp), _) if resp.status() == StatusCode::FORBIDDEN => {
+                tracing::warn!(path, url, "Origin denied access to the source");
+                FetchError::Upstream(Some(resp.status()))
+            }
+            (Ok(resp), _) => {
+                tracing::info!(path, "Request error: status code {}", resp.status());
+                FetchError::Upstream(Some(resp.status()))
+            }
+            (Err(err), _) => {
+                tracing::info!(path, "Request error {err:#}");
+                upstream_error(&err)
+            }
+        };
+
+        metrics::counter!("image_resize_upstream_errors_total").increment(1);
+        Err(err)
+    }
+}
+
+/// Body of `resp`, read chunk by chunk so that one growing past `max_bytes` is given up on without being buffered
+/// whole, whatever its `Content-Length` announced
+async fn read_body(path: &str, mut resp: reqwest::Response, max_bytes: Option<u64>) -> Result<Bytes, FetchError> {
+    let announced = resp.content_length().filter(|_| max_bytes.is_some()).unwrap_or(0);
+    let mut body = BytesMut::with_capacity(announced as usize);
+    loop {
+        match resp.chunk().await {
+            Ok(Some(chunk)) => {
+                body.extend_from_slice(&chunk);
+                check_size(path, body.len() as u64, max_bytes)?;
+            }
+            Ok(None) => return Ok(body.freeze()),
+            Err(err) => {
+                tracing::error!(path, "Request get bytes error {err:#}");
+                return Err(upstream_error(&err));
+            }
+        }
+    }
+}
+
+/// Failure of a request that got no (complete) answer
+fn upstream_error(err: &reqwest::Error) -> FetchError {
+    if err.is_timeout() {
+        FetchError::UpstreamTimeout
+    } else {
+        FetchError::Upstream(None)
+    }
+}
+
+fn cache_policy(headers: &HeaderMap) -> Option<CachePolicy> {
+    // Repeated headers are the same as a single comma separated one
+    let cache_control = headers
+        .get_all(header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect::<Vec<_>>()
+        .join(",");
+
+    CachePolicy::parse(&cache_control)
+}
+
+/// S3 compatible bucket, the path without its leading `/` is the object key
+pub struct S3Source {
+    store: AmazonS3,
+    max_bytes: Option<u64>,
+}
+
+impl S3Source {
+    pub fn new(store: AmazonS3, max_bytes: Option<u64>) -> Self {
+        Self { store, max_bytes }
+    }
+}
+
+#[async_trait]
+impl Source for S3Source {
+    async fn fetch(&self, path: &str, _forwarded: &HeaderMap) -> Result<Fetched, FetchError> {
+        // The key as is, `Path::from` would escape the characters S3 advises against, such as `%`, looking up
+        // another object
+        let key = match object_store::path::Path::parse(path.trim_start_matches('/')) {
+            Ok(key) => key,
+            Err(err) => {
+                tracing::info!(path, "Invalid object key {err}");
+                return Err(FetchError::NotFound);
+            }
+        };
+        let result = match self.store.get_opts(&key, GetOptions::default()).await {
+            Ok(result) => {
+                check_size(path, result.meta.size, self.max_bytes)?;
+                result.bytes().await
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(data) => Ok(Fetched::new(data)),
+            Err(object_store::Error::NotFound { .. }) => Err(FetchError::NotFound),
+            Err(err) => {
+                tracing::error!(path, "S3 get object error {err:#}");
+                metrics::counter!("image_resize_upstream_errors_total").increment(1);
+                Err(FetchError::Upstream(None))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn http_source(base_url: &str) -> HttpSource {
+        HttpSource::new(
+            Client::new(),
+            base_url.to_owned(),
+            None,
+            None,
+            Retry {
+                retries: 0,
+                budget: Duration::ZERO,
+            },
+            None,
+        )
+    }
+
+    /// Paths as the handler gives them to the sources, percent-decoded
+    fn decoded(path: &str) -> String {
+        percent_encoding::percent_decode_str(path)
+            .decode_utf8_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn url_encodes_segments() {
+        let source = http_source("https://cdn.example.com/images");
+        assert_eq!(
+            source.url(&decoded("/a%20b/c%253F.png")).as_deref(),
+            Some("https://cdn.example.com/images/a%20b/c%253F.png")
+        );
+        assert_eq!(
+            source.url("/a#b?.png").as_deref(),
+            Some("https://cdn.example.com/images/a%23b%3F.png")
+        );
+        // A `+` is a plus in paths, not a space
+        for path in ["/a+b.png", "/a%2Bb.png"] {
+            assert_eq!(
+                source.url(&decoded(path)).as_deref(),
+                Some("https://cdn.example.com/images/a+b.png"),
+                "{path}"
+            );
+        }
+        assert_eq!(
+            source.url(&decoded("/caf%C3%A9/%C3%A9.png")).as_deref(),
+            Some("https://cdn.example.com/images/caf%C3%A9/%C3%A9.png")
+        );
+    }
+
+    #[test]
+    fn url_rejects_dot_segments() {
+        let source = http_source("https://cdn.example.com/images/");
+        for path in [
+            "/..%2Fsecret.png",
+            "/%2e%2e/secret.png",
+            "/a/../b.png",
+            "/./a.png",
+            "/a//b.png",
+            "/a/",
+        ] {
+            assert_eq!(source.url(&decoded(path)), None, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn local_source_stays_in_folder() {
+        let root = std::env::temp_dir().join(format!("image-resize-source-{}", std::process::id()));
+        let folder = root.join("images");
+        std::fs::create_dir_all(&folder).unwrap();
+        std::fs::write(folder.join("inside.png"), b"inside").unwrap();
+        std::fs::write(root.join("secret.png"), b"secret").unwrap();
+
+        let source = LocalSource::new(&folder, None);
+        let forwarded = HeaderMap::new();
+        let fetched = source.fetch("/inside.png", &forwarded).await.unwrap();
+        assert_eq!(fetched.data, Bytes::from_static(b"inside"));
+        for path in ["/../secret.png", "/%2e%2e/secret.png", "/./../secret.png"] {
+            let result = source.fetch(&decoded(path), &forwarded).await;
+            assert!(matches!(result, Err(FetchError::NotFound)), "{path}");
+        }
+        let absolute = root.join("secret.png");
+        let result = source.fetch(absolute.to_str().unwrap(), &forwarded).await;
+        assert!(matches!(result, Err(FetchError::NotFound)));
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_source_rejects_escapes() {
+        let folder = std::env::temp_dir().join(format!("image-resize-escape-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("/etc/passwd", folder.join("passwd.png")).unwrap();
+
+        let source = LocalSource::new(&folder, None);
+        let forwarded = HeaderMap::new();
+        for path in [
+            "/../../etc/passwd",
+            "../../../../etc/passwd",
+            "/etc/passwd",
+            "/passwd.png",
+        ] {
+            let result = source.fetch(path, &forwarded).await;
+            assert!(matches!(result, Err(FetchError::NotFound)), "{path}");
+        }
+
+        std::fs::remove_dir_all(folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn local_source_decoded_names() {
+        let folder = std::env::temp_dir().join(format!("image-resize-names-{}", std::process::id()));
+        std::fs::create_dir_all(&folder).unwrap();
+        let names = ["a b.png", "a+b.png", "é.png"];
+        for name in names {
+            std::fs::write(folder.join(name), name).unwrap();
+        }
+
+        let source = LocalSource::new(&folder, None);
+        let forwarded = HeaderMap::new();
+        let mut fetched = Vec::new();
+        for path in ["/a%20b.png", "/a+b.png", "/%C3%A9.png"] {
+            fetched.push(
+                source
+                    .fetch(&decoded(path), &forwarded)
+                    .await
+                    .map(|fetched| fetched.data),
+            );
+        }
+        std::fs::remove_dir_all(folder).unwrap();
+
+        for (fetched, name) in fetched.into_iter().zip(names) {
+            assert!(matches!(fetched, Ok(data) if data == name.as_bytes()), "{name}");
+        }
+    }
+}
@@ -0,0 +1,120 @@
+use std::num::NonZeroU32;
+
+use fast_image_resize as fir;
+use serde::Serialize;
+
+use crate::depth;
+
+/// Grid of a sprite sheet: cells of `cell_width`x`cell_height`, `columns` per row and `gutter` pixels apart
+#[derive(Debug, Clone, Copy)]
+pub struct Layout {
+    pub columns: NonZeroU32,
+    pub gutter: u32,
+    pub cell_width: u32,
+    pub cell_height: u32,
+}
+
+/// Cell of a tile in the sheet
+#[derive(Debug, Serialize)]
+pub struct Placed {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Layout {
+    /// Dimensions of the sheet of `count` cells
+    pub fn size(&self, count: u32) -> (u64, u64) {
+        let columns = self.columns.get().min(count) as u64;
+        let rows = count.div_ceil(self.columns.get()) as u64;
+        let extent = |cells: u64, cell: u32| cells * cell as u64 + cells.saturating_sub(1) * self.gutter as u64;
+        (extent(columns, self.cell_width), extent(rows, self.cell_height))
+    }
+
+    /// Whether a tile of `width`x`height` fits in a cell
+    pub fn fits(&self, width: u32, height: u32) -> bool {
+        width <= self.cell_width && height <= self.cell_height
+    }
+}
+
+/// Composite the `tiles` in the cells of `layout`, in rows, each centered in its cell on a transparent sheet. The
+/// tiles must `fit` in the cells.
+pub fn compose(layout: &Layout, tiles: &[fir::Image]) -> (fir::Image<'static>, Vec<Placed>) {
+    let (cell_width, cell_height) = (layout.cell_width, layout.cell_height);
+    let (width, height) = layout.size(tiles.len() as u32);
+    let (width, height) = (width.max(1) as usize, height.max(1) as usize);
+
+    let mut buffer = vec![0; width * height * 4];
+    let mut placed = Vec::with_capacity(tiles.len());
+    for (index, tile) in tiles.iter().enumerate() {
+        let (column, row) = (index as u32 % layout.columns, index as u32 / layout.columns);
+        let (tile_width, tile_height) = (tile.width().get(), tile.height().get());
+        let (cell_x, cell_y) = (
+            column * (cell_width + layout.gutter),
+            row * (cell_height + layout.gutter),
+        );
+        let (x, y) = (
+            cell_x + (cell_width - tile_width) / 2,
+            cell_y + (cell_height - tile_height) / 2,
+        );
+
+        let rounded = depth::to_8_bit(tile);
+        let tile = rounded.as_ref().unwrap_or(tile);
+        let channels = if depth::has_alpha(tile) { 4 } else { 3 };
+        for (tile_y, src) in tile.buffer().chunks_exact(tile_width as usize * channels).enumerate() {
+            let start = ((y as usize + tile_y) * width + x as usize) * 4;
+            let dst = buffer[start..start + tile_width as usize * 4].chunks_exact_mut(4);
+            for (dst, src) in dst.zip(src.chunks_exact(channels)) {
+                dst[..3].copy_from_slice(&src[..3]);
+                dst[3] = src.get(3).copied().unwrap_or(u8::MAX);
+            }
+        }
+
+        placed.push(Placed {
+            x: cell_x,
+            y: cell_y,
+            width: cell_width,
+            height: cell_height,
+        });
+    }
+
+    let sheet = fir::Image::from_vec_u8(
+        NonZeroU32::new(width as u32).unwrap(),
+        NonZeroU32::new(height as u32).unwrap(),
+        buffer,
+        fir::PixelType::U8x4,
+    )
+    .unwrap();
+    (sheet, placed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(width: u32, height: u32) -> fir::Image<'static> {
+        let (width, height) = (NonZeroU32::new(width).unwrap(), NonZeroU32::new(height).unwrap());
+        fir::Image::new(width, height, fir::PixelType::U8x4)
+    }
+
+    #[test]
+    fn cells_of_requested_size() {
+        let layout = Layout {
+            columns: NonZeroU32::new(2).unwrap(),
+            gutter: 2,
+            cell_width: 20,
+            cell_height: 20,
+        };
+        assert_eq!(layout.size(3), (42, 42));
+        assert!(!layout.fits(20, 21));
+
+        let (sheet, placed) = compose(&layout, &[tile(20, 20), tile(20, 15), tile(10, 20)]);
+        assert_eq!((sheet.width().get(), sheet.height().get()), (42, 42));
+        let cells = placed
+            .iter()
+            .map(|placed| (placed.x, placed.y, placed.width, placed.height))
+            .collect::<Vec<_>>();
+        assert_eq!(cells, [(0, 0, 20, 20), (22, 0, 20, 20), (0, 22, 20, 20)]);
+    }
+}
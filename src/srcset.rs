@@ -0,0 +1,121 @@
+use std::{num::NonZeroU32, str::FromStr};
+
+use serde::{de, Deserialize, Deserializer, Serialize};
+
+use crate::{
+    resize::{self, Limits, ResizeOptions},
+    signature,
+};
+
+/// Widths a single manifest may list
+pub const MAX_WIDTHS: usize = 16;
+
+/// Query parameters replaced in the URLs of the variants
+const REPLACED_PARAMS: &[&str] = &["srcset", "width", "w", signature::SIGNATURE_PARAM];
+
+/// Output widths given as `320,640,1280`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Widths(pub Vec<NonZeroU32>);
+
+impl FromStr for Widths {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let widths = value
+            .split(',')
+            .map(|width| width.trim().parse::<NonZeroU32>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| format!("invalid srcset `{value}`: {err}"))?;
+        if widths.len() > MAX_WIDTHS {
+            return Err(format!("srcset has more than {MAX_WIDTHS} widths"));
+        }
+
+        Ok(Widths(widths))
+    }
+}
+
+impl<'de> Deserialize<'de> for Widths {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+/// Responsive variants of an image, answered instead of the image itself
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    /// Dimensions of the source, upright
+    pub width: u32,
+    pub height: u32,
+    /// Ready to use `srcset` attribute value
+    pub srcset: String,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Variant {
+    pub url: String,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// List the variants of the source for each of the `widths`, their URLs keeping the other parameters of the request.
+///
+/// `path` and `query` are the raw (still percent-encoded) ones of the request, the URLs are signed with `secret`
+/// when signatures are required.
+#[allow(clippy::too_many_arguments)]
+pub fn manifest(
+    path: &str,
+    query: &str,
+    secret: Option<&[u8]>,
+    source: (u32, u32),
+    widths: &Widths,
+    options: &ResizeOptions,
+    limits: &Limits,
+) -> Result<Manifest, String> {
+    let (src_width, src_height) = (NonZeroU32::new(source.0).unwrap(), NonZeroU32::new(source.1).unwrap());
+    let kept = query
+        .split('&')
+        .filter(|pair| !pair.is_empty() && !REPLACED_PARAMS.contains(&pair.split('=').next().unwrap_or_default()))
+        .collect::<Vec<_>>();
+
+    let mut variants = Vec::with_capacity(widths.0.len());
+    for &width in &widths.0 {
+        let options = ResizeOptions {
+            width: Some(width),
+            ..*options
+        };
+        let plan = resize::plan(src_width, src_height, &options)?;
+        plan.check(limits)?;
+
+        let mut query = kept.join("&");
+        if !query.is_empty() {
+            query.push('&');
+        }
+        query.push_str(&format!("width={width}"));
+        if let Some(secret) = secret {
+            let signature = signature::sign(secret, path, &query);
+            query.push_str(&format!("&{}={signature}", signature::SIGNATURE_PARAM));
+        }
+
+        // Padded outputs are the size of their canvas
+        let (width, height) = plan.canvas.unwrap_or((plan.width, plan.height));
+        variants.push(Variant {
+            url: format!("{path}?{query}"),
+            width: width.get(),
+            height: height.get(),
+        });
+    }
+
+    let srcset = variants
+        .iter()
+        .map(|variant| format!("{} {}w", variant.url, variant.width))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    Ok(Manifest {
+        width: source.0,
+        height: source.1,
+        srcset,
+        variants,
+    })
+}
@@ -0,0 +1,80 @@
+use std::sync::{Arc, OnceLock};
+
+use fast_image_resize as fir;
+use image::{DynamicImage, RgbaImage};
+use resvg::{tiny_skia, usvg};
+
+/// Whether the source looks like an SVG document, `<svg` or an XML prolog followed by it
+pub fn is_svg(bytes: &[u8]) -> bool {
+    let head = &bytes[..bytes.len().min(1024)];
+    let head = head.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(head);
+    let head = head.trim_ascii_start();
+    if head.starts_with(b"<svg") {
+        return true;
+    }
+
+    (head.starts_with(b"<?xml") || head.starts_with(b"<!")) && head.windows(4).any(|window| window == b"<svg")
+}
+
+pub fn parse(bytes: &[u8]) -> Result<usvg::Tree, usvg::Error> {
+    usvg::Tree::from_data(bytes, options())
+}
+
+/// Size of the document in pixels, rounded up
+pub fn size(tree: &usvg::Tree) -> (u32, u32) {
+    let size = tree.size();
+    (
+        size.width().ceil().max(1.0) as u32,
+        size.height().ceil().max(1.0) as u32,
+    )
+}
+
+/// Dimensions of the raster of the `crop` region of the document (the whole of it when absent) scaled by `scale`
+pub fn render_size(tree: &usvg::Tree, scale: f32, crop: Option<fir::CropBox>) -> (u32, u32) {
+    let (width, height) = crop.map_or_else(|| size(tree), |crop| (crop.width.get(), crop.height.get()));
+    (
+        (width as f32 * scale).round().max(1.0) as u32,
+        (height as f32 * scale).round().max(1.0) as u32,
+    )
+}
+
+/// Render the `crop` region of the document (the whole of it when absent) scaled by `scale`
+pub fn rasterize(tree: &usvg::Tree, scale: f32, crop: Option<fir::CropBox>) -> Option<DynamicImage> {
+    let (width, height) = render_size(tree, scale, crop);
+    let mut pixmap = tiny_skia::Pixmap::new(width, height)?;
+
+    let (left, top) = crop.map_or((0, 0), |crop| (crop.left, crop.top));
+    let transform = tiny_skia::Transform::from_translate(-(left as f32), -(top as f32)).post_scale(scale, scale);
+    resvg::render(tree, transform, &mut pixmap.as_mut());
+
+    // tiny-skia renders premultiplied alpha
+    let (width, height) = (pixmap.width(), pixmap.height());
+    let buffer = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+
+    RgbaImage::from_raw(width, height, buffer).map(DynamicImage::ImageRgba8)
+}
+
+fn options() -> &'static usvg::Options<'static> {
+    static OPTIONS: OnceLock<usvg::Options<'static>> = OnceLock::new();
+    OPTIONS.get_or_init(|| {
+        let mut fonts = usvg::fontdb::Database::new();
+        fonts.load_system_fonts();
+
+        usvg::Options {
+            fontdb: Arc::new(fonts),
+            // Only embedded `data:` images, `href`s must not read files off the server
+            image_href_resolver: usvg::ImageHrefResolver {
+                resolve_data: usvg::ImageHrefResolver::default_data_resolver(),
+                resolve_string: Box::new(|_, _| None),
+            },
+            ..usvg::Options::default()
+        }
+    })
+}
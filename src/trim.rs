@@ -0,0 +1,64 @@
+use std::num::NonZeroU32;
+
+use fast_image_resize as fir;
+
+use crate::resize::CropRect;
+
+/// Tolerance of `trim` when the request has none, enough for the noise of JPEG compression around flat borders
+pub const DEFAULT_TOLERANCE: f32 = 10.0;
+
+/// Region of `image` (or of its requested `region`) left once the border of the color of its top-left pixel is cut
+/// away, as ImageMagick's `-trim`. A channel may differ from the border by `tolerance` percent and the pixel still be
+/// part of it, fully transparent pixels all being alike. `None` when the whole image is the border color.
+pub fn bounds(image: &fir::Image, region: Option<CropRect>, tolerance: f32) -> Option<CropRect> {
+    let (width, height) = (image.width().get(), image.height().get());
+    let region = region.unwrap_or(CropRect {
+        x: 0,
+        y: 0,
+        width: image.width(),
+        height: image.height(),
+    });
+    // A crop out of the image is rejected by the resize plan
+    if region.x.checked_add(region.width.get())? > width || region.y.checked_add(region.height.get())? > height {
+        return None;
+    }
+
+    let channels = if image.pixel_type() == fir::PixelType::U8x4 {
+        4
+    } else {
+        3
+    };
+    let row_len = width as usize * channels;
+    let pixel = |x: u32, y: u32| {
+        let start = y as usize * row_len + x as usize * channels;
+        &image.buffer()[start..start + channels]
+    };
+    let max_difference = (tolerance.clamp(0.0, 100.0) / 100.0 * 255.0).round() as u8;
+    let border = pixel(region.x, region.y);
+    let is_border = |pixel: &[u8]| {
+        if channels == 4 && pixel[3] == 0 && border[3] == 0 {
+            return true;
+        }
+        pixel.iter().zip(border).all(|(&a, &b)| a.abs_diff(b) <= max_difference)
+    };
+
+    let (mut left, mut top, mut right, mut bottom) = (u32::MAX, u32::MAX, 0, 0);
+    for y in region.y..region.y + region.height.get() {
+        for x in region.x..region.x + region.width.get() {
+            if !is_border(pixel(x, y)) {
+                (left, top) = (left.min(x), top.min(y));
+                (right, bottom) = (right.max(x), bottom.max(y));
+            }
+        }
+    }
+    if left == u32::MAX {
+        return None;
+    }
+
+    Some(CropRect {
+        x: left,
+        y: top,
+        width: NonZeroU32::new(right - left + 1).unwrap(),
+        height: NonZeroU32::new(bottom - top + 1).unwrap(),
+    })
+}
@@ -0,0 +1,39 @@
+use std::{
+    io,
+    os::unix::fs::FileTypeExt,
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use hyper::server::accept::Accept;
+use tokio::net::{UnixListener, UnixStream};
+
+/// Connections accepted on a Unix domain socket
+pub struct UnixAccept(UnixListener);
+
+impl UnixAccept {
+    /// Listen on `path`, replacing the socket a previous run left behind. Any other kind of file is kept and fails
+    /// the bind.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        match std::fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "not a socket")),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+
+        UnixListener::bind(path).map(Self)
+    }
+}
+
+impl Accept for UnixAccept {
+    type Conn = UnixStream;
+    type Error = io::Error;
+
+    fn poll_accept(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<io::Result<Self::Conn>>> {
+        self.0
+            .poll_accept(cx)
+            .map(|result| Some(result.map(|(stream, _)| stream)))
+    }
+}
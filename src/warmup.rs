@@ -0,0 +1,130 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderValue, Request, Uri},
+    Router,
+};
+use serde::{de, Deserialize, Deserializer};
+use tokio::{sync::Semaphore, task::JoinSet};
+use tower::ServiceExt;
+
+/// Warm-up requests processed at once, leaving room for the traffic served meanwhile
+const CONCURRENCY: usize = 4;
+/// `Accept` of the warm-up requests, the one of current browsers so the outputs they negotiate get cached
+const ACCEPT: HeaderValue = HeaderValue::from_static("image/avif,image/webp,image/apng,image/*,*/*;q=0.8");
+
+/// Extension of the warm-up requests, which the rate limit lets through
+#[derive(Debug, Clone, Copy)]
+pub struct WarmUp;
+
+/// Requests to warm the cache up with
+#[derive(Debug, Clone)]
+pub struct Manifest(Vec<Uri>);
+
+impl<'de> Deserialize<'de> for Manifest {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        load(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// Read the `/path?query` lines of the `--warmup` manifest, blank lines and `#` comments skipped
+pub fn load(path: &str) -> Result<Manifest, String> {
+    let manifest = std::fs::read_to_string(path).map_err(|err| format!("failed to read {path}: {err}"))?;
+    manifest
+        .lines()
+        .enumerate()
+        .map(|(number, line)| (number + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| match line.parse::<Uri>() {
+            Ok(uri) if uri.scheme().is_none() && line.starts_with('/') => Ok(uri),
+            _ => Err(format!("{path}:{number}: `{line}` isn't a `/path?query`")),
+        })
+        .collect::<Result<_, _>>()
+        .map(Manifest)
+}
+
+/// Request every URI of the manifest from the app, as a client would, so the outputs land in the cache
+pub async fn run(app: Router, Manifest(uris): Manifest) {
+    let start = Instant::now();
+    let total = uris.len();
+    tracing::info!("Warming up the cache with {total} images");
+
+    let permits = Arc::new(Semaphore::new(CONCURRENCY));
+    let done = Arc::new(AtomicUsize::new(0));
+    let mut tasks = JoinSet::new();
+    for uri in uris {
+        let permit = permits
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the semaphore is never closed");
+        let (app, done) = (app.clone(), done.clone());
+        tasks.spawn(async move {
+            let request = Request::get(uri.clone())
+                .header(header::ACCEPT, ACCEPT)
+                .extension(WarmUp)
+                .body(Body::empty())
+                .expect("the URI is valid");
+            let status = match app.oneshot(request).await {
+                Ok(resp) => resp.status(),
+                Err(err) => match err {},
+            };
+            drop(permit);
+
+            let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+            if status.is_success() {
+                tracing::info!(%uri, "Warmed up {done}/{total}");
+            } else {
+                tracing::warn!(%uri, %status, "Failed to warm up {done}/{total}");
+            }
+            status.is_success()
+        });
+    }
+
+    let mut warmed = 0;
+    while let Some(result) = tasks.join_next().await {
+        warmed += usize::from(result.unwrap_or(false));
+    }
+    tracing::info!(
+        "Cache warm-up finished in {:.1}s, {warmed}/{total} images processed",
+        start.elapsed().as_secs_f32()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{middleware, routing::get};
+
+    use super::*;
+    use crate::RateLimiter;
+
+    #[tokio::test]
+    async fn not_rate_limited() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let limiter = Arc::new(RateLimiter::new(1.0));
+        let app = Router::new()
+            .route(
+                "/a.png",
+                get({
+                    let hits = hits.clone();
+                    move || async move {
+                        hits.fetch_add(1, Ordering::Relaxed);
+                    }
+                }),
+            )
+            .layer(middleware::from_fn(move |req, next| {
+                crate::rate_limit(limiter.clone(), false, req, next)
+            }));
+
+        let uris = ["/a.png?w=10", "/a.png?w=20", "/a.png?w=30"];
+        run(app, Manifest(uris.iter().map(|uri| uri.parse().unwrap()).collect())).await;
+        assert_eq!(hits.load(Ordering::Relaxed), uris.len());
+    }
+}
@@ -0,0 +1,118 @@
+use std::{num::NonZeroU32, sync::Arc};
+
+use fast_image_resize as fir;
+use serde::{de, Deserialize, Deserializer};
+use sha2::{Digest, Sha256};
+
+use crate::resize::Gravity;
+
+/// Gap between the watermark and the edges of the output, relative to its smallest side
+const MARGIN: f32 = 0.02;
+
+/// Image stamped on every output, RGBA with premultiplied alpha
+#[derive(Clone)]
+pub struct Watermark(Arc<fir::Image<'static>>);
+
+/// Where and how the watermark is stamped
+pub struct Placement {
+    pub gravity: Gravity,
+    /// From 0 (invisible) to 1 (the watermark's own alpha)
+    pub opacity: f32,
+    /// Width of the watermark relative to the output width, it is shrunk further when it would be taller than it
+    pub size: f32,
+}
+
+impl<'de> Deserialize<'de> for Watermark {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        load(&String::deserialize(deserializer)?).map_err(de::Error::custom)
+    }
+}
+
+/// Load the watermark image, for `--watermark`
+pub fn load(path: &str) -> Result<Watermark, String> {
+    let image = image::open(path).map_err(|err| format!("failed to load {path}: {err}"))?;
+    let image = fir::Image::from_vec_u8(
+        NonZeroU32::new(image.width()).unwrap(),
+        NonZeroU32::new(image.height()).unwrap(),
+        image.to_rgba8().into_raw(),
+        fir::PixelType::U8x4,
+    )
+    .unwrap();
+
+    // Resizing straight alpha would bleed the color of transparent pixels into the edges
+    let mut premultiplied = fir::Image::new(image.width(), image.height(), fir::PixelType::U8x4);
+    fir::MulDiv::default()
+        .multiply_alpha(&image.view(), &mut premultiplied.view_mut())
+        .expect("source and destination have the same size and pixel type");
+
+    Ok(Watermark(Arc::new(premultiplied)))
+}
+
+impl Watermark {
+    /// SHA-256 of the size and pixels of the watermark, telling it apart from others in the cache keys
+    pub fn digest(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.0.width().get().to_le_bytes());
+        hasher.update(self.0.height().get().to_le_bytes());
+        hasher.update(self.0.buffer());
+        hasher.finalize().into()
+    }
+
+    /// Composite the watermark over `image`, scaled to its size
+    pub fn apply(&self, image: fir::Image<'static>, placement: &Placement) -> fir::Image<'static> {
+        let (width, height, pixel_type) = (image.width(), image.height(), image.pixel_type());
+        let (mark_width, mark_height) = (self.0.width().get() as f32, self.0.height().get() as f32);
+        let ratio = f32::min(
+            width.get() as f32 * placement.size.clamp(0.0, 1.0) / mark_width,
+            height.get() as f32 / mark_height,
+        );
+        let scaled = |value: f32, max: NonZeroU32| ((value * ratio).round() as u32).clamp(1, max.get());
+        let (stamp_width, stamp_height) = (scaled(mark_width, width), scaled(mark_height, height));
+
+        let mut stamp = fir::Image::new(
+            NonZeroU32::new(stamp_width).unwrap(),
+            NonZeroU32::new(stamp_height).unwrap(),
+            fir::PixelType::U8x4,
+        );
+        fir::Resizer::new(fir::ResizeAlg::Convolution(fir::FilterType::Lanczos3))
+            .resize(&self.0.view(), &mut stamp.view_mut())
+            .expect("source and destination have the same pixel type");
+
+        let margin = (width.get().min(height.get()) as f32 * MARGIN).round() as u32;
+        let (horizontal, vertical) = placement.gravity.centering();
+        let offset = |room: u32, centering: f32| {
+            let margin = margin.min(room / 2);
+            margin + ((room - 2 * margin) as f32 * centering).round() as u32
+        };
+        let left = offset(width.get() - stamp_width, horizontal) as usize;
+        let top = offset(height.get() - stamp_height, vertical) as usize;
+
+        let channels = if pixel_type == fir::PixelType::U8x4 { 4 } else { 3 };
+        let row_len = width.get() as usize * channels;
+        let opacity = placement.opacity.clamp(0.0, 1.0);
+        let mut buffer = image.into_vec();
+        for (y, row) in stamp.buffer().chunks_exact(stamp_width as usize * 4).enumerate() {
+            let start = (top + y) * row_len + left * channels;
+            let dst = buffer[start..start + stamp_width as usize * channels].chunks_exact_mut(channels);
+            for (dst, src) in dst.zip(row.chunks_exact(4)) {
+                let alpha = src[3] as f32 / 255.0 * opacity;
+                if alpha == 0.0 {
+                    continue;
+                }
+
+                // Source over destination, the destination having straight alpha
+                let dst_alpha = dst.get(3).map_or(1.0, |&alpha| alpha as f32 / 255.0);
+                let out_alpha = alpha + dst_alpha * (1.0 - alpha);
+                for channel in 0..3 {
+                    let color = src[channel] as f32 * opacity + dst[channel] as f32 * dst_alpha * (1.0 - alpha);
+                    dst[channel] = (color / out_alpha).round().clamp(0.0, 255.0) as u8;
+                }
+                if channels == 4 {
+                    dst[3] = (out_alpha * 255.0).round() as u8;
+                }
+            }
+        }
+
+        fir::Image::from_vec_u8(width, height, buffer, pixel_type).unwrap()
+    }
+}